@@ -0,0 +1,19 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Sets ANDROIDFS_BUILD_ID to the commit and time of the build, so that a driver and daemon from different builds can be told apart
+fn main() {
+    let commit = match Command::new("git").arg("rev-parse").arg("--short").arg("HEAD").output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        _ => "unknown".to_string()
+    };
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0
+    };
+
+    println!("cargo:rustc-env=ANDROIDFS_BUILD_ID={}+{}.{}", std::env::var("CARGO_PKG_VERSION").unwrap(), commit, timestamp);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+}
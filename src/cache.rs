@@ -27,6 +27,11 @@ impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
         cache.remove(key);
     }
 
+    pub fn clear(&self) {
+        let mut cache = self.cache.write().unwrap();
+        cache.clear();
+    }
+
     pub fn put(&self, key: K, value: V) {
         let mut cache = self.cache.write().unwrap();
         if cache.len() >= self.max_size {
@@ -40,13 +45,24 @@ impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
         });
     }
 
+    // Returns the value even if it has expired, e.g. to check with the server whether it is still up to date
+    pub fn try_get_stale(&self, key: &K) -> Option<V> {
+        let cache = self.cache.read().unwrap();
+        cache.get(key).map(|item| item.value.clone())
+    }
+
     pub fn try_get(&self, key: &K) -> Option<V> {
+        self.try_get_with(key, |value| value.clone())
+    }
+
+    // Like try_get, but only clones what f picks out of the value rather than all of it
+    pub fn try_get_with<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
         let cache = self.cache.read().unwrap();
 
         match cache.get(key) {
             Some(item) => {
                 if item.cache_time.elapsed() < self.invalidation_time {
-                    Some(item.value.clone())
+                    Some(f(&item.value))
                 }   else {
                     None
                 }
@@ -1,20 +1,27 @@
-use std::io::Read;
-use std::{io::Write, convert::TryInto};
-use std::net::TcpStream;
-use std::sync::{Mutex, MutexGuard};
-use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use std::net::{TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::collections::HashMap;
+use std::time::SystemTime;
 use dokan::OperationError;
 use winapi::shared::ntstatus::*;
 use serde::de::DeserializeOwned;
 
+use crate::log::*;
 use crate::models::*;
+use crate::protocol;
 use crate::requests;
 use crate::responses;
 
 pub enum Error {
     IOFailed(std::io::Error),
     ReceivedInvalidData(bincode::Error),
-    RequestFailed(responses::Error)
+    RequestFailed(responses::Error),
+    // The server uses the given protocol version, so the rest of its hello cannot be read
+    IncompatibleVersion(u32),
+    AuthenticationFailed,
+    Disconnected
 }
 
 impl From<std::io::Error> for Error {
@@ -49,59 +56,281 @@ pub fn convert_error(err: Error) -> OperationError {
     let nt_status = match err {
         Error::IOFailed(_)  => STATUS_INTERNAL_ERROR,
         Error::ReceivedInvalidData(_) => STATUS_INTERNAL_ERROR,
-        Error::RequestFailed(err) => match err {
-            responses::Error::FileNotFound => STATUS_INVALID_DEVICE_REQUEST,
-            responses::Error::NoSuchHandle => STATUS_INVALID_DEVICE_REQUEST,
-            responses::Error::FileExists => STATUS_INVALID_DEVICE_REQUEST,
-            responses::Error::PermissionDenied => STATUS_ACCESS_DENIED,
-            responses::Error::Other => STATUS_INTERNAL_ERROR,
-            responses::Error::CouldNotFindDisk => STATUS_NOT_IMPLEMENTED
+        Error::IncompatibleVersion(_) => STATUS_INTERNAL_ERROR,
+        Error::AuthenticationFailed => STATUS_ACCESS_DENIED,
+        Error::Disconnected => STATUS_DEVICE_NOT_CONNECTED,
+        Error::RequestFailed(err) => {
+            debug!("Request failed: {}", err);
+            match err.kind {
+                responses::ErrorKind::FileNotFound => STATUS_OBJECT_NAME_NOT_FOUND,
+                responses::ErrorKind::NoSuchHandle => STATUS_INVALID_HANDLE,
+                responses::ErrorKind::FileExists => STATUS_OBJECT_NAME_COLLISION,
+                responses::ErrorKind::PermissionDenied => STATUS_ACCESS_DENIED,
+                responses::ErrorKind::CouldNotFindDisk => STATUS_NOT_IMPLEMENTED,
+                responses::ErrorKind::NoSpace => STATUS_DISK_FULL,
+                responses::ErrorKind::ReadOnly => STATUS_MEDIA_WRITE_PROTECTED,
+                responses::ErrorKind::InvalidRequest => STATUS_INVALID_PARAMETER,
+                responses::ErrorKind::NotSupported => STATUS_NOT_IMPLEMENTED,
+                responses::ErrorKind::NotADirectory => STATUS_NOT_A_DIRECTORY,
+                responses::ErrorKind::IsADirectory => STATUS_FILE_IS_A_DIRECTORY,
+                responses::ErrorKind::DirectoryNotEmpty => STATUS_DIRECTORY_NOT_EMPTY,
+                responses::ErrorKind::ReadOnlyFilesystem => STATUS_MEDIA_WRITE_PROTECTED,
+                responses::ErrorKind::NameTooLong => STATUS_NAME_TOO_LONG,
+                responses::ErrorKind::CrossDevice => STATUS_NOT_SAME_DEVICE,
+                responses::ErrorKind::Busy => STATUS_SHARING_VIOLATION,
+                responses::ErrorKind::TooManyOpenFiles => STATUS_TOO_MANY_OPENED_FILES,
+                responses::ErrorKind::Interrupted => STATUS_CANCELLED,
+                responses::ErrorKind::Other => STATUS_INTERNAL_ERROR
+            }
         }
     };
 
     OperationError::NtStatus(nt_status)
 }
 
+type PendingMap = HashMap<protocol::RequestId, Sender<Vec<u8>>>;
+
+// Largest amount of data sent in each Write, which must leave the rest of the message well within protocol::MAX_MESSAGE_LENGTH
+const WRITE_CHUNK_SIZE: usize = 1024 * 1024;
+
 pub struct Client {
-    connection: Mutex<TcpStream>
+    writer: Mutex<TcpStream>,
+    // Used to pass responses to the threads waiting on them. Set to None once the connection is lost
+    pending: Arc<Mutex<Option<PendingMap>>>,
+    next_request_id: AtomicU32,
+    server_hello: responses::Hello
+}
+
+// Receives the responses to a request that has been sent, and stops waiting for them once dropped
+struct PendingRequest<'a> {
+    client: &'a Client,
+    request_id: protocol::RequestId,
+    receiver: Receiver<Vec<u8>>
+}
+
+impl<'a> PendingRequest<'a> {
+    fn receive<T: DeserializeOwned>(&self) -> Result<T> {
+        let payload = match self.receiver.recv() {
+            Ok(payload) => payload,
+            Err(_) => return Err(Error::Disconnected)
+        };
+
+        let deserialized: responses::Result<T> = protocol::deserialize(&payload[..])?;
+        Ok(deserialized?)
+    }
+}
+
+impl<'a> Drop for PendingRequest<'a> {
+    fn drop(&mut self) {
+        unregister(&self.client.pending, self.request_id);
+    }
+}
+
+// Stops passing responses to a request to the thread that was waiting for them
+fn unregister(pending: &Mutex<Option<PendingMap>>, request_id: protocol::RequestId) {
+    if let Some(pending) = pending.lock().unwrap().as_mut() {
+        pending.remove(&request_id);
+    }
+}
+
+// Passes the events of a watch to the subscriber until the watch stops or the subscriber goes away
+fn forward_watch_events(receiver: Receiver<Vec<u8>>, subscriber: Sender<responses::WatchEvent>, pending: Arc<Mutex<Option<PendingMap>>>, watch_id: protocol::RequestId) {
+    for payload in receiver {
+        let event = match protocol::deserialize::<responses::Result<responses::WatchEvent>>(&payload[..]) {
+            Ok(Ok(event)) => event,
+            Ok(Err(err)) => {
+                warn!("Watch {} failed: {}", watch_id, err);
+                break;
+            },
+            Err(err) => {
+                warn!("Received invalid event for watch {}: {}", watch_id, err);
+                continue;
+            }
+        };
+
+        let stopped = matches!(event, responses::WatchEvent::Stopped);
+        if subscriber.send(event).is_err() || stopped {
+            break;
+        }
+    }
+
+    unregister(&pending, watch_id);
+}
+
+// Reads responses from the server and passes each one to the thread waiting for it
+fn receive_responses(mut tcp_stream: TcpStream, pending: Arc<Mutex<Option<PendingMap>>>) {
+    loop {
+        let (request_id, payload) = match protocol::read_message(&mut tcp_stream) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Connection to daemon lost: {}", err);
+                break;
+            }
+        };
+
+        match pending.lock().unwrap().as_ref().and_then(|pending| pending.get(&request_id)) {
+            Some(sender) => { let _ = sender.send(payload); },
+            None => debug!("Discarding response to request {}, which is no longer being waited on", request_id)
+        }
+    }
+
+    // Dropping the senders wakes up any threads still waiting for a response
+    *pending.lock().unwrap() = None;
 }
 
 impl Client {
-    pub fn new(tcp_stream: TcpStream) -> Client {
-        Client {
-            connection: Mutex::new(tcp_stream)
+    // Performs the handshake with the server, failing if it uses a different protocol version or does not accept the token
+    // If read_only is true, the server will reject any modifications made through this client
+    pub fn new(mut tcp_stream: TcpStream, token: &str, read_only: bool) -> Result<Client> {
+        protocol::write_frame(&mut tcp_stream, &requests::Hello {
+            protocol_version: protocol::PROTOCOL_VERSION,
+            token: token.to_string(),
+            read_only: read_only
+        })?;
+
+        // Every version starts its hello with the protocol version, but the rest may differ
+        let payload = protocol::read_frame(&mut tcp_stream)?;
+        let protocol_version: u32 = protocol::deserialize(&payload[..])?;
+        if protocol_version != protocol::PROTOCOL_VERSION {
+            return Err(Error::IncompatibleVersion(protocol_version));
+        }
+        let server_hello: responses::Hello = protocol::deserialize(&payload[..])?;
+        if !server_hello.authenticated {
+            return Err(Error::AuthenticationFailed);
         }
+
+        let pending = Arc::new(Mutex::new(Some(PendingMap::new())));
+        {
+            let read_stream = tcp_stream.try_clone()?;
+            let pending = pending.clone();
+            std::thread::spawn(move || receive_responses(read_stream, pending));
+        }
+
+        Ok(Client {
+            writer: Mutex::new(tcp_stream),
+            pending: pending,
+            next_request_id: AtomicU32::new(1),
+            server_hello: server_hello
+        })
+    }
+
+    pub fn server_hello(&self) -> &responses::Hello {
+        &self.server_hello
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.server_hello.read_only
     }
 
-    pub fn send_keep_connection<T: DeserializeOwned>(&self, request: requests::Request) -> Result<(T, MutexGuard<TcpStream>)> {
-        let mut connection = self.connection.lock().unwrap();
-        
-        let encoded = bincode::serialize(&request).unwrap();
-        connection.write_u64::<BigEndian>(encoded.len().try_into().unwrap()).unwrap();
-        connection.write(&encoded[..]).unwrap();
+    fn begin_request(&self, request: requests::Request) -> Result<PendingRequest<'_>> {
+        let (request_id, receiver) = self.register()?;
 
-        let length = connection.read_u64::<BigEndian>().unwrap();
-        let mut buffer = vec![0u8; length as usize];
+        // Created before sending so that the request is unregistered if sending fails
+        let pending_request = PendingRequest {
+            client: self,
+            request_id: request_id,
+            receiver: receiver
+        };
 
-        connection.read_exact(&mut buffer[..]).unwrap();
+        protocol::write_message(&mut *self.writer.lock().unwrap(), request_id, &request)?;
+        Ok(pending_request)
+    }
+
+    // Allocates an ID for a request, and a channel that its responses will be passed to
+    fn register(&self) -> Result<(protocol::RequestId, Receiver<Vec<u8>>)> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(request_id, sender),
+            None => return Err(Error::Disconnected)
+        };
 
-        let deserialized: responses::Result<T> = bincode::deserialize(&buffer[..])?;
-        let data = deserialized?;
-        Ok((data, connection))
+        Ok((request_id, receiver))
     }
-    
-    pub fn send<'a, T: DeserializeOwned>(&self, request: requests::Request) -> Result<T> {
-        Ok(self.send_keep_connection::<T>(request)?.0)
+
+    pub fn send<T: DeserializeOwned>(&self, request: requests::Request) -> Result<T> {
+        self.begin_request(request)?.receive()
     }
 
-    pub fn list_files(&self, path: &str) -> Result<responses::ListFiles> {
-        self.send(requests::Request::List(path.to_string()))
+    // Returns the token that a listing of path would have, without the listing itself
+    pub fn list_token(&self, path: &str, follow_symlinks: bool) -> Result<responses::ListToken> {
+        self.send(requests::Request::ListToken(requests::ListToken {
+            path: path.to_string(),
+            follow_symlinks: follow_symlinks
+        }))
+    }
+
+    pub fn open_directory(&self, path: &str, follow_symlinks: bool) -> Result<responses::OpenDirectory> {
+        self.send(requests::Request::OpenDirectory(requests::OpenDirectory {
+            path: path.to_string(),
+            follow_symlinks: follow_symlinks
+        }))
+    }
+
+    pub fn read_directory(&self, handle: DirectoryHandle, max_entries: u32) -> Result<responses::ReadDirectory> {
+        self.send(requests::Request::ReadDirectory(requests::ReadDirectory {
+            handle: handle,
+            max_entries: max_entries
+        }))
+    }
+
+    pub fn close_directory(&self, handle: DirectoryHandle) -> Result<()> {
+        self.send(requests::Request::CloseDirectory(handle))
     }
 
     pub fn get_free_space(&self) -> Result<responses::FreeSpace> {
         self.send(requests::Request::GetFreeSpace)
     }
 
+    // Describes the filesystem containing path
+    pub fn stat_fs(&self, path: &str) -> Result<responses::StatFs> {
+        self.send(requests::Request::StatFs(path.to_string()))
+    }
+
+    // Lists the storage volumes on the device, such as internal storage and SD cards
+    pub fn list_volumes(&self) -> Result<responses::ListVolumes> {
+        self.send(requests::Request::ListVolumes)
+    }
+
+    // Sends every change to path to subscriber until unwatch is called, after which WatchEvent::Stopped is sent
+    // Returns the ID of the watch, to pass to unwatch
+    pub fn watch(&self, path: String, recursive: bool, subscriber: Sender<responses::WatchEvent>) -> Result<protocol::RequestId> {
+        // The events keep arriving after this returns, so they are received on another thread instead of through a PendingRequest
+        let (watch_id, receiver) = self.register()?;
+        let request = requests::Request::Watch(requests::Watch {
+            path: path,
+            recursive: recursive
+        });
+
+        if let Err(err) = self.start_watch(watch_id, &receiver, request) {
+            unregister(&self.pending, watch_id);
+            return Err(err);
+        }
+
+        let pending = self.pending.clone();
+        std::thread::spawn(move || forward_watch_events(receiver, subscriber, pending, watch_id));
+        Ok(watch_id)
+    }
+
+    // Sends a Watch request and waits for the watch to start
+    fn start_watch(&self, watch_id: protocol::RequestId, receiver: &Receiver<Vec<u8>>, request: requests::Request) -> Result<()> {
+        protocol::write_message(&mut *self.writer.lock().unwrap(), watch_id, &request)?;
+
+        let payload = match receiver.recv() {
+            Ok(payload) => payload,
+            Err(_) => return Err(Error::Disconnected)
+        };
+        let event: responses::Result<responses::WatchEvent> = protocol::deserialize(&payload[..])?;
+        match event? {
+            responses::WatchEvent::Started => Ok(()),
+            _ => Err(Error::ReceivedInvalidData(Box::new(bincode::ErrorKind::Custom("Watch did not start with WatchEvent::Started".to_string()))))
+        }
+    }
+
+    pub fn unwatch(&self, watch_id: protocol::RequestId) -> Result<()> {
+        self.send(requests::Request::Unwatch(watch_id))
+    }
+
     pub fn close_file(&self, handle: FileHandle) -> Result<()> {
         self.send(requests::Request::Close(handle))
     }
@@ -110,9 +339,33 @@ impl Client {
         self.send(requests::Request::Delete(path))
     }
 
-    pub fn open_file(&self, path: String) -> Result<FileHandle> {
+    pub fn create_file(&self, path: String, mode: u32, exclusive: bool, create_parents: bool) -> Result<()> {
+        self.send(requests::Request::CreateFile(requests::CreateFile {
+            path: path,
+            mode: mode,
+            exclusive: exclusive,
+            create_parents: create_parents
+        }))
+    }
+
+    pub fn create_directory(&self, path: String, mode: u32, exclusive: bool, create_parents: bool) -> Result<()> {
+        self.send(requests::Request::CreateDirectory(requests::CreateDirectory {
+            path: path,
+            mode: mode,
+            exclusive: exclusive,
+            create_parents: create_parents
+        }))
+    }
+
+    // mode is only used if the file is created
+    pub fn open_file(&self, path: String, access: requests::AccessMode, disposition: requests::Disposition, mode: u32, no_follow: bool, directory_only: bool) -> Result<FileHandle> {
         self.send(requests::Request::Open(requests::OpenFile {
-            path: path
+            path: path,
+            access: access,
+            disposition: disposition,
+            mode: mode,
+            no_follow: no_follow,
+            directory_only: directory_only
         }))
     }
 
@@ -131,23 +384,40 @@ impl Client {
             len: buffer.len() as u64
         });
 
-        // Send the read request first to receive the length read
-        let (receive_result, mut connection) = self.send_keep_connection::<responses::ReadFile>(req)?;
-        let length_read = receive_result as usize;
-
-        connection.read_exact(&mut buffer[0..length_read])?;
-        Ok(length_read as u32)
+        let pending = self.begin_request(req)?;
+        let mut length_read = 0;
+        loop {
+            match pending.receive::<responses::ReadChunk>()? {
+                responses::ReadChunk::Data(data) => {
+                    let end = std::cmp::min(length_read + data.len(), buffer.len());
+                    buffer[length_read..end].copy_from_slice(&data[0..end - length_read]);
+                    length_read = end;
+                },
+                responses::ReadChunk::End(_) => return Ok(length_read as u32)
+            }
+        }
     }
 
-    pub fn write_file(&self, handle: FileHandle, offset: u64, data: &[u8]) -> Result<()> {
-        let req = requests::Request::Write(requests::WriteFile {
-            handle: handle,
-            offset: offset,
-            len: data.len() as u64
-        });
-        let (_, mut connection) = self.send_keep_connection::<()>(req)?;
-        connection.write_all(data)?;
-        Ok(())
+    // Returns the number of bytes written, which may be less than the length of data
+    // Large writes are sent a chunk at a time, returning the total number of bytes written
+    pub fn write_file(&self, handle: FileHandle, offset: u64, data: &[u8]) -> Result<u32> {
+        let mut written = 0;
+        for chunk in data.chunks(WRITE_CHUNK_SIZE) {
+            let req = requests::Request::Write(requests::WriteFile {
+                handle: handle,
+                offset: offset + written,
+                data: chunk.to_vec()
+            });
+
+            let chunk_written: responses::WriteFile = self.send(req)?;
+            written += chunk_written;
+            // The server only writes less than it was sent if it could not write any more
+            if chunk_written < chunk.len() as u64 {
+                break;
+            }
+        }
+
+        Ok(written as u32)
     }
 
     pub fn set_end_of_file(&self, handle: FileHandle, len: u64) -> Result<()> {
@@ -159,7 +429,88 @@ impl Client {
         self.send(req)
     }
 
-    pub fn stat_file(&self, path: &str) -> Result<responses::StatFile> {
-        self.send(requests::Request::Stat(path.to_string()))
+    // Timestamps that are None are left unchanged
+    pub fn set_times(&self, target: requests::FileTarget, accessed: Option<SystemTime>, modified: Option<SystemTime>) -> Result<()> {
+        self.send(requests::Request::SetTimes(requests::SetTimes {
+            target: target,
+            accessed: accessed,
+            modified: modified
+        }))
+    }
+
+    pub fn set_mode(&self, target: requests::FileTarget, mode: u32) -> Result<()> {
+        self.send(requests::Request::SetMode(requests::SetMode {
+            target: target,
+            mode: mode
+        }))
+    }
+
+    // IDs that are None are left unchanged
+    pub fn set_owner(&self, target: requests::FileTarget, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.send(requests::Request::SetOwner(requests::SetOwner {
+            target: target,
+            uid: uid,
+            gid: gid
+        }))
+    }
+
+    // Waits until everything written through the handle has reached the disk
+    // If data_only is true, metadata that is not needed to read the data back may not be synced
+    pub fn flush(&self, handle: FileHandle, data_only: bool) -> Result<()> {
+        self.send(requests::Request::Flush(requests::Flush {
+            handle: handle,
+            data_only: data_only
+        }))
+    }
+
+    // Syncs the filesystem containing path, or every filesystem on the device if path is None
+    pub fn sync_filesystem(&self, path: Option<String>) -> Result<()> {
+        self.send(requests::Request::SyncFilesystem(path))
+    }
+
+    // Returns Conditional::NotModified if the stat still has the token if_changed
+    pub fn stat_file(&self, path: &str, follow_symlinks: bool, if_changed: Option<ChangeToken>) -> Result<responses::StatFile> {
+        self.send(requests::Request::Stat(requests::StatFile {
+            path: path.to_string(),
+            follow_symlinks: follow_symlinks,
+            if_changed: if_changed
+        }))
+    }
+
+    pub fn stat_many(&self, paths: Vec<String>, follow_symlinks: bool) -> Result<responses::StatMany> {
+        self.send(requests::Request::StatMany(requests::StatMany {
+            paths: paths,
+            follow_symlinks: follow_symlinks
+        }))
+    }
+
+    // Sends several requests that query metadata in one round trip
+    // Returns the response to each of them, in the order they were given
+    pub fn batch(&self, requests: Vec<requests::Request>) -> Result<responses::Batch> {
+        self.send(requests::Request::Batch(requests))
+    }
+
+    pub fn read_link(&self, path: &str) -> Result<responses::ReadLink> {
+        self.send(requests::Request::ReadLink(path.to_string()))
+    }
+
+    // target is stored in the link as is, so may be relative to the directory containing it
+    pub fn create_symlink(&self, path: String, target: String) -> Result<()> {
+        self.send(requests::Request::CreateSymlink(requests::CreateSymlink {
+            path: path,
+            target: target
+        }))
+    }
+
+    // Stops the server, which will also disconnect any other clients
+    pub fn shutdown(&self) -> Result<()> {
+        self.send(requests::Request::Shutdown)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Also stops the thread receiving responses, which holds its own handle to the stream
+        let _ = self.writer.lock().unwrap().shutdown(Shutdown::Both);
     }
 }
\ No newline at end of file
@@ -9,12 +9,14 @@ extern crate serde;
 extern crate log;
 extern crate env_logger;
 extern crate tempfile;
+extern crate rand;
 
 mod adb;
 mod client;
 mod cache;
 mod file_system;
 mod models;
+mod protocol;
 mod requests;
 mod responses;
 
@@ -27,15 +29,28 @@ use std::io::Write;
 use std::net::{SocketAddrV4, Ipv4Addr};
 
 use std::{time::Duration, sync::{Arc, Mutex}, net::TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use adb::Invokeable;
+use rand::Rng;
 
 use client::Client;
 
+// Port that the daemon listens on, on the device
+const DAEMON_PORT: u16 = 12345;
+
 const BASE_PORT: u16 = 15000;
 const MAX_PORT: u16 = 16000;
 
 const SERVER_EXECUTABLE: &[u8] = include_bytes!("../target/aarch64-linux-android/release/androidfs_server");
 const SERVER_PUSH_PATH: &str = "/data/local/tmp/androidfs_server";
+// Where the daemon records its process ID, so that a daemon left running by an earlier launch can be stopped
+const SERVER_PID_PATH: &str = "/data/local/tmp/androidfs_server.pid";
+
+// Length of the random token that the daemon requires clients to authenticate with
+const TOKEN_LENGTH: usize = 32;
+// The token is given to the daemon in a file within a directory that only the shell user can enter, which the daemon deletes once read
+const TOKEN_DIRECTORY: &str = "/data/local/tmp/androidfs";
+const TOKEN_PATH: &str = "/data/local/tmp/androidfs/token";
 
 // List the mount points in the order we prefer to use them
 const MOUNT_POINTS: &[&'static str] = &[
@@ -69,7 +84,10 @@ enum SetupError {
 	NoAvailableDriveLetter,
 	AdbError(adb::Error),
 	IOError(std::io::Error),
-	DaemonUnreachable
+	DaemonUnreachable,
+	HandshakeFailed,
+	IncompatibleDaemon(u32),
+	AuthenticationFailed
 }
 
 impl From<adb::Error> for SetupError {
@@ -92,11 +110,37 @@ impl std::fmt::Display for SetupError {
 			Self::NoAvailableDriveLetter => f.write_str("No drive letter available"),
             Self::DaemonUnreachable => f.write_str("Failed to connect to the daemon"),
             Self::IOError(err) => f.write_fmt(format_args!("An IO error occurred while extracting the daemon: {}", err)),
+            Self::HandshakeFailed => f.write_str("Failed to complete the handshake with the daemon"),
+            Self::IncompatibleDaemon(protocol_version) => f.write_fmt(format_args!("Daemon uses protocol version {}, but the driver uses version {}",
+				protocol_version, protocol::PROTOCOL_VERSION)),
+            Self::AuthenticationFailed => f.write_str("The daemon rejected our token, another instance of it may already be running"),
 		}?;
 		Ok(())
     }
 }
 
+// Devices listed in ANDROIDFS_READ_ONLY (comma separated serial numbers, or * for every device) are mounted read-only
+fn is_read_only(serial_number: &str) -> bool {
+	match std::env::var("ANDROIDFS_READ_ONLY") {
+		Ok(devices) => devices.split(',').map(|device| device.trim()).any(|device| device == "*" || device == serial_number),
+		Err(_) => false
+	}
+}
+
+// Paths on the device that the daemon allows access to, listed in ANDROIDFS_ROOTS (comma separated), or just the storage volumes by default
+fn allowed_roots() -> Vec<String> {
+	let roots: Vec<String> = match std::env::var("ANDROIDFS_ROOTS") {
+		Ok(roots) => roots.split(',').map(|root| root.trim().to_string()).filter(|root| !root.is_empty()).collect(),
+		Err(_) => Vec::new()
+	};
+
+	if roots.is_empty() {
+		vec!["/storage".to_string()]
+	}	else	{
+		roots
+	}
+}
+
 fn setup(device: adb::Device, drive_map: Arc<Mutex<HashSet<String>>>) -> Result<String, SetupError> {
 	info!("Attempting to mount {}", device.serial_number);
 
@@ -105,7 +149,7 @@ fn setup(device: adb::Device, drive_map: Arc<Mutex<HashSet<String>>>) -> Result<
 	let mut chosen_port: Option<u16> = None;
 	for port in BASE_PORT..=MAX_PORT {
 		debug!("Attempting forward to {}", port);
-		match device.invoke_result(vec!["forward".to_string(), format!("tcp:{}", port), "tcp:12345".to_string()]) {
+		match device.invoke_result(vec!["forward".to_string(), format!("tcp:{}", port), format!("tcp:{}", DAEMON_PORT)]) {
 			Ok(_) => {
 				chosen_port = Some(port);
 				break;
@@ -124,13 +168,16 @@ fn setup(device: adb::Device, drive_map: Arc<Mutex<HashSet<String>>>) -> Result<
 	};
 	debug!("Forwarded to {}", port);
 
-	let mount_point = match MOUNT_POINTS
-	.iter()
-	.filter(|p| !std::path::Path::new(&format!("{}\\", p)).exists())
-	.next() {
-		Some(mount_point) => mount_point,
-		None => return Err(SetupError::NoAvailableDriveLetter)
-	};
+	// Drive letters that the device's volumes are mounted at, so that they can be unmounted once it is unplugged
+	let mount_points: Arc<Mutex<Vec<&'static str>>> = Arc::new(Mutex::new(Vec::new()));
+
+	// A daemon from an earlier launch (e.g. before the driver restarted) would keep the port, and only accepts its own token
+	// It also cannot be overwritten while it is running, so it is stopped first
+	// The process ID is checked in case it has since been reused by another process
+	device.invoke_shell_command_result(vec![format!(
+		"if [ -f {pid_path} ] && grep -q androidfs_server /proc/$(cat {pid_path})/cmdline 2>/dev/null; then kill $(cat {pid_path}); sleep 1; fi; rm -f {pid_path}",
+		pid_path = SERVER_PID_PATH
+	)])?;
 
 	let mut file = tempfile::NamedTempFile::new()?;
 	file.write_all(SERVER_EXECUTABLE)?;
@@ -138,14 +185,47 @@ fn setup(device: adb::Device, drive_map: Arc<Mutex<HashSet<String>>>) -> Result<
 	// Push the daemon and make it executable
 	device.invoke_result(vec!["push".to_string(), file.path().to_str().unwrap().to_string(), SERVER_PUSH_PATH.to_string()])?;
 	device.invoke_shell_command_result(vec!["chmod".to_string(), "555".to_string(), "/data/local/tmp/androidfs_server".to_string()])?;
-	
+
+	// Stops other apps on the device from connecting to the daemon
+	let token: String = rand::thread_rng()
+		.sample_iter(&rand::distributions::Alphanumeric)
+		.take(TOKEN_LENGTH)
+		.map(char::from)
+		.collect();
+
+	// Passing the token on the command line would let any process on the device read it for as long as the daemon runs
+	device.invoke_shell_command_result(vec![format!(
+		"mkdir -p {directory} && chmod 700 {directory} && rm -f {path}",
+		directory = TOKEN_DIRECTORY,
+		path = TOKEN_PATH
+	)])?;
+	let mut token_file = tempfile::NamedTempFile::new()?;
+	token_file.write_all(token.as_bytes())?;
+	device.invoke_result(vec!["push".to_string(), token_file.path().to_str().unwrap().to_string(), TOKEN_PATH.to_string()])?;
+
 	{
+		let roots = allowed_roots();
 		let drive_map = drive_map.clone();
 		let device = device.clone();
+		let mount_points = mount_points.clone();
 		std::thread::spawn(move || {
 			debug!("Hello from daemon thread");
 			let device = device;
-			match device.invoke_shell_command_result(vec!["./data/local/tmp/androidfs_server".to_string()]) {
+			let mut command = vec![
+				"./data/local/tmp/androidfs_server".to_string(),
+				"--token-file".to_string(),
+				TOKEN_PATH.to_string(),
+				"--port".to_string(),
+				DAEMON_PORT.to_string(),
+				"--pid-file".to_string(),
+				SERVER_PID_PATH.to_string()
+			];
+			for root in roots {
+				command.push("--root".to_string());
+				command.push(root);
+			}
+
+			match device.invoke_shell_command_result(command) {
 				Ok(_) => {},
 				Err(err) => {
 					error!("Invoking daemon failed: {}", err)
@@ -158,8 +238,10 @@ fn setup(device: adb::Device, drive_map: Arc<Mutex<HashSet<String>>>) -> Result<
 			// e.g. an error during startup
 			// If we unmounted in that case it could lead us to unmount another device
 			if drive_map.lock().unwrap().contains(&device.serial_number) {
-				if !dokan::unmount(U16CString::from_str(mount_point).unwrap()) {
-					warn!("Failed to unmount {}", device.serial_number)
+				for mount_point in mount_points.lock().unwrap().iter() {
+					if !dokan::unmount(U16CString::from_str(mount_point).unwrap()) {
+						warn!("Failed to unmount {} from {}", device.serial_number, mount_point)
+					}
 				}
 		
 				info!("Device {} disconnected", device.serial_number);
@@ -176,31 +258,84 @@ fn setup(device: adb::Device, drive_map: Arc<Mutex<HashSet<String>>>) -> Result<
 		Err(_) => return Err(SetupError::DaemonUnreachable)
 	};
 
-	let volume_name = U16CString::from_str(device.serial_number.clone()).unwrap();
-	let flags = MountFlags::CASE_SENSITIVE;
+	let client = match Client::new(tcp_stream, &token, is_read_only(&device.serial_number)) {
+		Ok(client) => client,
+		Err(client::Error::IncompatibleVersion(protocol_version)) => return Err(SetupError::IncompatibleDaemon(protocol_version)),
+		Err(client::Error::AuthenticationFailed) => return Err(SetupError::AuthenticationFailed),
+		Err(_) => return Err(SetupError::HandshakeFailed)
+	};
+	debug!("Connected to daemon build {}, capabilities: {:?}", client.server_hello().build_id, client.server_hello().capabilities);
+
+	let mut flags = MountFlags::CASE_SENSITIVE;
+	if client.is_read_only() {
+		flags |= MountFlags::WRITE_PROTECT;
+	}
+	let client = Arc::new(client);
+
+	// Each storage volume gets a drive of its own, falling back to a single drive for the first allowed root if none can be found
+	let fallback_root = allowed_roots().remove(0);
+	let volumes: Vec<(String, String)> = match client.list_volumes() {
+		Ok(volumes) if !volumes.is_empty() => volumes.into_iter()
+			.map(|volume| (volume.path, format!("{} ({})", volume.label, device.serial_number)))
+			.collect(),
+		Ok(_) => vec![(fallback_root, device.serial_number.clone())],
+		Err(_) => {
+			warn!("Failed to list the volumes of {}, mounting {} instead", device.serial_number, fallback_root);
+			vec![(fallback_root, device.serial_number.clone())]
+		}
+	};
+
+	// The device is only forgotten once every one of its drives has been unmounted
+	let remaining_mounts = Arc::new(AtomicUsize::new(0));
+	for (root, label) in volumes {
+		let mount_point = {
+			let mut mount_points = mount_points.lock().unwrap();
+			let mount_point = match MOUNT_POINTS
+			.iter()
+			.filter(|p| !std::path::Path::new(&format!("{}\\", p)).exists() && !mount_points.contains(p))
+			.next() {
+				Some(mount_point) => *mount_point,
+				None if mount_points.is_empty() => return Err(SetupError::NoAvailableDriveLetter),
+				None => {
+					warn!("No drive letter available for {}, skipping the rest of the volumes of {}", root, device.serial_number);
+					break;
+				}
+			};
+			mount_points.push(mount_point);
+			mount_point
+		};
 
-	{
 		let drive_map = drive_map.clone();
 		let device = device.clone();
+		let client = client.clone();
+		let remaining_mounts = remaining_mounts.clone();
+		let mount_points = mount_points.clone();
+		remaining_mounts.fetch_add(1, Ordering::SeqCst);
 
 		// Start a new thread which mounts the drive (once the drive is mounted, the thread is blocked)
 		std::thread::spawn(move || {
+			debug!("Mounting {} of {} at {}", root, device.serial_number, mount_point);
 			match Drive::new()
 			.mount_point(&U16CString::from_str(mount_point).unwrap())
 			.flags(flags)
 			.thread_count(0)
-			.mount(&QuestFsHandler::new(Client::new(tcp_stream), volume_name.clone())) {
+			.mount(&QuestFsHandler::new(client, U16CString::from_str(label).unwrap(), root)) {
 				Ok(_) => debug!("Mount thread exited"),
 				Err(err) => {
 					error!("Mount error: {}", err);
 				}
 			};
-			drive_map.lock().unwrap().remove(&device.serial_number);
+
+			// The drive letter may be reused by a later launch, which the daemon thread of this one must not unmount
+			mount_points.lock().unwrap().retain(|p| *p != mount_point);
+			if remaining_mounts.fetch_sub(1, Ordering::SeqCst) == 1 {
+				drive_map.lock().unwrap().remove(&device.serial_number);
+			}
 		});
 	}
 
 	drive_map.lock().unwrap().insert(device.serial_number.clone()); // Avoid this device getting added again
-	Ok(mount_point.to_string())
+	Ok(mount_points.lock().unwrap().join(", "))
 }
 
 fn main() {
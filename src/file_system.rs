@@ -1,4 +1,7 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 
 use crate::client;
 use crate::cache::Cache;
@@ -10,63 +13,344 @@ use winapi::shared::ntstatus::*;
 use crate::log::*;
 use crate::models::FileHandle;
 use crate::models;
+use crate::protocol;
+use crate::requests;
+use crate::responses;
+
+// Values of create_disposition passed to create_file
+const FILE_SUPERSEDE: u32 = 0;
+const FILE_OPEN: u32 = 1;
+const FILE_CREATE: u32 = 2;
+const FILE_OPEN_IF: u32 = 3;
+const FILE_OVERWRITE: u32 = 4;
+const FILE_OVERWRITE_IF: u32 = 5;
+
+// Flags within create_options passed to create_file
+const FILE_DIRECTORY_FILE: u32 = 0x00000001;
+const FILE_NON_DIRECTORY_FILE: u32 = 0x00000040;
+
+// Permissions given to files and directories created through the drive, before the umask is applied
+const DEFAULT_FILE_MODE: u32 = 0o666;
+const DEFAULT_DIRECTORY_MODE: u32 = 0o777;
+
+// Number of entries requested at a time when listing a directory
+const DIRECTORY_PAGE_SIZE: u32 = 256;
 
 pub struct QuestFsHandler {
     volume_name: U16CString,
-	client: client::Client,
-	directory_cache: Cache<String, Result<Vec<models::FileInfo>, OperationError>>,
-	stat_cache: Cache<String, Result<models::FileInfo, OperationError>>
+	// Path on the device that the root of the drive corresponds to
+	root: String,
+	// Shared with the handlers of the device's other volumes
+	client: Arc<client::Client>,
+	// Shared with the thread that invalidates entries as the server reports changes
+	directory_cache: Arc<Cache<String, Result<Versioned<Listing>, OperationError>>>,
+	stat_cache: Arc<Cache<String, Result<Versioned<models::FileInfo>, OperationError>>>,
+	// If changes are not being watched, the caches are only invalidated by time
+	watch: Arc<Mutex<WatchState>>
+}
+
+// Changes are watched from another thread, since the server walks the whole tree before the watch starts
+enum WatchState {
+	Starting,
+	Watching(protocol::RequestId),
+	// The handler has been dropped, or changes could not be watched
+	Stopped
+}
+
+// A listing or stat, along with the token to check whether it is still up to date once it expires from the cache
+#[derive(Clone)]
+struct Versioned<T> {
+	value: T,
+	token: Option<models::ChangeToken>
 }
 
+// Entries of a directory, indexed by name so that the listing can also answer stats of them
+#[derive(Clone)]
+struct Listing {
+	files: Vec<models::FileInfo>,
+	// Position of each entry within files
+	by_name: HashMap<String, usize>
+}
 
-// Converts winnnt to *nix file names (\ to /)
-fn convert_file_name(win_file_name: &U16CStr) -> String {
-	win_file_name.to_string_lossy().replace("\\", "/")
+impl Listing {
+	fn new(files: Vec<models::FileInfo>) -> Listing {
+		let by_name = files.iter().enumerate().map(|(index, file)| (file.name.clone(), index)).collect();
+		Listing {
+			files: files,
+			by_name: by_name
+		}
+	}
+
+	fn get(&self, name: &str) -> Option<&models::FileInfo> {
+		self.by_name.get(name).map(|index| &self.files[*index])
+	}
 }
 
+// Linux file type bits within a file mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
 // Approximates the linux file mode using winnt file attributes
+// Files are listed with symlinks followed, so a link to a directory is described by the directory's mode
 fn convert_attributes(file: &models::FileInfo) -> u32 {
 	// Tell windows that our files take longer to access
 	// Unfortunately it doesn't seem as though this actually changes the way it treats them (it will still try to open all the images in a folder to preview them, for example)
 	let mut attributes = FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS | FILE_ATTRIBUTE_RECALL_ON_OPEN;
 
-	if file.mode & 0xa000 == 0xa000 {
-		// Symlinks
-		attributes |= FILE_ATTRIBUTE_DIRECTORY | /* TODO: Explorer does not recognise this and leaves the icon as is */ FILE_ATTRIBUTE_REPARSE_POINT;
+	if file.link.is_some() {
+		// TODO: Explorer does not recognise this and leaves the icon as is
+		attributes |= FILE_ATTRIBUTE_REPARSE_POINT;
 	}
-	if file.mode & 0x4000 == 0x4000 {
+	if file.mode & S_IFMT == S_IFDIR {
 		attributes |= FILE_ATTRIBUTE_DIRECTORY;
 	}
 
 	attributes
 }
 
+// Picks the access mode to open a file with based on the access requested by windows
+// Truncating a file requires write access, and a file must be opened with some access even if only its attributes are wanted
+fn convert_access(desired_access: winnt::ACCESS_MASK, disposition: requests::Disposition) -> requests::AccessMode {
+	let write = desired_access & (winnt::GENERIC_WRITE | winnt::GENERIC_ALL | winnt::FILE_WRITE_DATA) != 0
+		|| disposition == requests::Disposition::TruncateExisting
+		|| disposition == requests::Disposition::Supersede;
+	let append = !write && desired_access & winnt::FILE_APPEND_DATA != 0;
+	let read = desired_access & (winnt::GENERIC_READ | winnt::GENERIC_ALL | winnt::FILE_READ_DATA | winnt::MAXIMUM_ALLOWED) != 0;
+
+	requests::AccessMode {
+		read: read || (!write && !append),
+		write: write,
+		append: append
+	}
+}
+
+fn convert_find_data(file: &models::FileInfo) -> FindData {
+	FindData {
+		attributes: convert_attributes(file),
+		creation_time: file.creation_time,
+		last_access_time: file.last_accessed,
+		last_write_time: file.last_modified,
+		file_size: file.size,
+		file_name: U16CString::from_str(file.name.as_str()).unwrap()
+	}
+}
+
+// Combines the reply to a conditional request with the stale value whose token was sent with it
+fn revalidate<T>(response: client::Result<responses::Conditional<T>>, stale: Option<Versioned<T>>) -> Result<Versioned<T>, OperationError> {
+	match client::convert_response(response)? {
+		responses::Conditional::Modified { value, token } => Ok(Versioned {
+			value: value,
+			token: Some(token)
+		}),
+		// Only sent if we gave a token, which we only do when there is a stale value
+		responses::Conditional::NotModified => stale.ok_or(OperationError::NtStatus(STATUS_INTERNAL_ERROR))
+	}
+}
+
+// Returns the expired entry for file_name if it can be revalidated
+fn find_stale<T: Clone>(cache: &Cache<String, Result<Versioned<T>, OperationError>>, file_name: &String) -> Option<Versioned<T>> {
+	match cache.try_get_stale(file_name) {
+		Some(Ok(stale)) if stale.token.is_some() => Some(stale),
+		_ => None
+	}
+}
+
+// Returns the time to set, or None if it should be left unchanged
+fn convert_file_time(time: FileTimeInfo) -> Option<SystemTime> {
+	match time {
+		FileTimeInfo::SetTime(time) => Some(time),
+		_ => None
+	}
+}
+
 impl QuestFsHandler {
-    pub fn new(client: client::Client, volume_name: U16CString) -> Self {
-        QuestFsHandler { 
+    pub fn new(client: Arc<client::Client>, volume_name: U16CString, root: String) -> Self {
+        let handler = QuestFsHandler { 
             volume_name: volume_name,
+            root: root,
             client: client,
 			// TODO: Test these values more and see what is reasonable in terms of accuracy and speed
-            directory_cache: Cache::new(Duration::from_secs(10), 128),
-			stat_cache: Cache::new(Duration::from_secs(3), 1024)
-        }
+            directory_cache: Arc::new(Cache::new(Duration::from_secs(10), 128)),
+			stat_cache: Arc::new(Cache::new(Duration::from_secs(3), 1024)),
+			watch: Arc::new(Mutex::new(WatchState::Starting))
+        };
+		handler.watch_for_changes();
+		handler
     }
 
+	// Invalidates cached entries as soon as the server reports that they changed, e.g. because an app on the device wrote to them
+	fn watch_for_changes(&self) {
+		// Watching the whole device would mean watching every directory of /proc and /sys, so the caches are left to expire instead
+		if self.root == "/" {
+			*self.watch.lock().unwrap() = WatchState::Stopped;
+			return;
+		}
+
+		let root = self.root.clone();
+		let client = self.client.clone();
+		let watch = self.watch.clone();
+		let directory_cache = self.directory_cache.clone();
+		let stat_cache = self.stat_cache.clone();
+		std::thread::spawn(move || {
+			let (sender, receiver) = mpsc::channel();
+			let watch_id = match client.watch(root.clone(), true, sender) {
+				Ok(watch_id) => watch_id,
+				Err(_) => {
+					warn!("Failed to watch {} for changes, relying on the caches expiring instead", root);
+					*watch.lock().unwrap() = WatchState::Stopped;
+					return;
+				}
+			};
+
+			{
+				let mut watch = watch.lock().unwrap();
+				match *watch {
+					// The handler was dropped while the watch was starting, so nothing else will stop it
+					WatchState::Stopped => {
+						let _ = client.unwatch(watch_id);
+						return;
+					},
+					_ => *watch = WatchState::Watching(watch_id)
+				};
+			}
+
+			let invalidate = |file_name: &String| {
+				stat_cache.erase(file_name);
+				directory_cache.erase(file_name);
+				match std::path::Path::new(file_name.as_str()).parent() {
+					Some(parent) => directory_cache.erase(&parent.to_string_lossy().to_string()),
+					None => {}
+				};
+			};
+
+			for event in receiver {
+				match event {
+					responses::WatchEvent::Changed(change) => {
+						if let responses::ChangeKind::Renamed { from } = &change.kind {
+							invalidate(from);
+						}
+						invalidate(&change.path);
+					},
+					responses::WatchEvent::Overflowed => {
+						stat_cache.clear();
+						directory_cache.clear();
+					},
+					responses::WatchEvent::Started | responses::WatchEvent::Stopped => {}
+				}
+			}
+			debug!("Stopped watching for changes");
+		});
+	}
+
+	// Converts winnnt to *nix file names (\ to /), relative to the root of the volume
+	fn convert_file_name(&self, win_file_name: &U16CStr) -> String {
+		let file_name = win_file_name.to_string_lossy().replace("\\", "/");
+
+		if self.root == "/" {
+			file_name
+		}	else if file_name == "/" {
+			self.root.clone()
+		}	else	{
+			format!("{}{}", self.root, file_name)
+		}
+	}
+
 	// Stats or returns the cached stat of file_name
+	// An expired stat is revalidated with the server, so that it is only sent again if the file has changed
 	fn stat_file(&self, file_name: String) -> Result<models::FileInfo, OperationError> {
-		match self.stat_cache.try_get(&file_name) {
-			Some(cached) => cached,
-			None => {
-				let stat_result = client::convert_response(self.client.stat_file(&file_name));
+		if let Some(cached) = self.stat_cache.try_get(&file_name) {
+			return cached.map(|stat| stat.value);
+		}
+		if let Some(listed) = self.find_in_listing(&file_name) {
+			return Ok(listed);
+		}
+
+		let stale = find_stale(&self.stat_cache, &file_name);
+		let token = stale.as_ref().and_then(|stale| stale.token);
+		let stat_result = revalidate(self.client.stat_file(&file_name, true, token), stale);
 
-				self.stat_cache.put(file_name, stat_result.clone());
-				stat_result
+		self.stat_cache.put(file_name, stat_result.clone());
+		stat_result.map(|stat| stat.value)
+	}
+
+	// Looks file_name up in the cached listing of its directory, which describes it just as a stat would
+	// Returns None if there is no such listing or the name is not in it, in which case the file needs to be stat-ed
+	// A missing name does not mean that the file does not exist, as the storage may match names case-insensitively
+	fn find_in_listing(&self, file_name: &str) -> Option<models::FileInfo> {
+		let path = std::path::Path::new(file_name);
+		let directory = path.parent()?.to_string_lossy().to_string();
+		let name = path.file_name()?.to_string_lossy().to_string();
+
+		self.directory_cache.try_get_with(&directory, |listing| match listing {
+			Ok(listing) => listing.value.get(&name).cloned(),
+			Err(_) => None
+		})?
+	}
+
+	// Caches a listing of file_name, which find_in_listing also uses to answer stats of its entries without asking the server
+	fn cache_listing(&self, file_name: String, listing: Result<Versioned<Listing>, OperationError>) {
+		self.directory_cache.put(file_name, listing);
+	}
+
+	// Lists file_name a page at a time, passing each entry to fill as soon as its page arrives
+	// Returns every entry once the whole directory has been read
+	fn list_directory(&self, file_name: &str, mut fill: impl FnMut(&models::FileInfo) -> Result<(), OperationError>) -> Result<Versioned<Listing>, OperationError> {
+		let handle = client::convert_response(self.client.open_directory(file_name, true))?;
+
+		let mut files = Vec::new();
+		let result = loop {
+			let page = match client::convert_response(self.client.read_directory(handle, DIRECTORY_PAGE_SIZE)) {
+				Ok(page) => page,
+				Err(err) => break Err(err)
+			};
+			if let Err(err) = page.entries.iter().try_for_each(|file| fill(file)) {
+				break Err(err);
+			}
+
+			files.extend(page.entries);
+			if page.end {
+				break Ok(Versioned {
+					value: Listing::new(files),
+					token: page.token
+				});
+			}
+		};
+
+		// The server closes any directories left open once we disconnect, so failing to close one here is harmless
+		let _ = self.client.close_directory(handle);
+		result
+	}
+
+	// Lists a directory that has no cached listing, passing entries on as they arrive so that explorer can show the first of a large directory while the rest are read
+	fn find_files_uncached(&self, file_name: String, mut fill_find_data: impl FnMut(&FindData) -> Result<(), FillDataError>) -> Result<(), OperationError> {
+		let mut fill_failed = false;
+		let result = self.list_directory(&file_name, |file| match fill_find_data.call_mut((&convert_find_data(file),)) {
+			Ok(_) => Ok(()),
+			Err(err) => {
+				fill_failed = true;
+				Err(err.into())
+			}
+		});
+
+		// A listing that was cut short by fill_find_data failing is incomplete, so only errors from the server are cached
+		match result {
+			Ok(files) => {
+				self.cache_listing(file_name, Ok(files));
+				Ok(())
+			},
+			Err(err) => {
+				if !fill_failed {
+					self.cache_listing(file_name, Err(err.clone()));
+				}
+				Err(err)
 			}
 		}
 	}
 
 	fn trigger_update(&self, file_name: &String) {
 		self.stat_cache.erase(file_name);
+		// Listings also answer stats, so a directory's own listing must not outlive it
+		self.directory_cache.erase(file_name);
 		match std::path::Path::new(file_name.as_str()).parent() {
 			Some(parent) => self.directory_cache.erase(&parent.to_string_lossy().to_string()),
 			None => {}
@@ -74,6 +358,16 @@ impl QuestFsHandler {
 	}
 }
 
+impl Drop for QuestFsHandler {
+	fn drop(&mut self) {
+		let watch = std::mem::replace(&mut *self.watch.lock().unwrap(), WatchState::Stopped);
+		if let WatchState::Watching(watch_id) = watch {
+			// The client is shared with the device's other drives, so may outlive this one
+			let _ = self.client.unwatch(watch_id);
+		}
+	}
+}
+
 impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
     type Context = FileHandle;
 
@@ -81,43 +375,68 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 		&'b self,
 		win_file_name: &U16CStr,
 		_security_context: &DOKAN_IO_SECURITY_CONTEXT,
-		_desired_access: winnt::ACCESS_MASK,
+		desired_access: winnt::ACCESS_MASK,
 		_file_attributes: u32,
 		_share_access: u32,
 		create_disposition: u32,
-		_create_options: u32,
+		create_options: u32,
 		_info: &mut OperationInfo<'a, 'b, Self>,
 	) -> Result<CreateFileInfo<Self::Context>, OperationError> {
-		// TODO: Finish this method (use memfs as reference)
-
-		let file_name = convert_file_name(win_file_name);
-
-		// TODO: Define constants for these
-		if create_disposition == 1 || create_disposition == 3 { // OPEN/OPEN-IF
-			let stat = self.stat_file(file_name.clone())?;
-
-			if convert_attributes(&stat) & FILE_ATTRIBUTE_DIRECTORY == FILE_ATTRIBUTE_DIRECTORY {
-				return Ok(CreateFileInfo {
-					context: 0,
-					is_dir: true,
-					new_file_created: true
-				})
-			}
+		let file_name = self.convert_file_name(win_file_name);
+
+		let disposition = match create_disposition {
+			FILE_SUPERSEDE | FILE_OVERWRITE_IF => requests::Disposition::Supersede,
+			FILE_OPEN => requests::Disposition::Open,
+			FILE_CREATE => requests::Disposition::CreateNew,
+			FILE_OPEN_IF => requests::Disposition::OpenOrCreate,
+			FILE_OVERWRITE => requests::Disposition::TruncateExisting,
+			_ => return Err(OperationError::NtStatus(STATUS_INVALID_PARAMETER))
+		};
 
-			let handle = client::convert_response(self.client.open_file(file_name))?;
+		let existing = self.stat_file(file_name.clone());
+		let is_dir = match &existing {
+			Ok(stat) => convert_attributes(stat) & FILE_ATTRIBUTE_DIRECTORY == FILE_ATTRIBUTE_DIRECTORY,
+			Err(_) => create_options & FILE_DIRECTORY_FILE == FILE_DIRECTORY_FILE
+		};
 
-			Ok(CreateFileInfo {
-				context: handle,
-				is_dir: false,
-				new_file_created: false
-			})
-		}	else	{
-			Ok(CreateFileInfo {
+		// Directories are not opened on the server, so they only need to exist
+		if is_dir {
+			let new_file_created = match existing {
+				Ok(_) if create_options & FILE_NON_DIRECTORY_FILE == FILE_NON_DIRECTORY_FILE => return Err(OperationError::NtStatus(STATUS_FILE_IS_A_DIRECTORY)),
+				Ok(_) if disposition == requests::Disposition::CreateNew => return Err(OperationError::NtStatus(STATUS_OBJECT_NAME_COLLISION)),
+				Ok(_) => false,
+				Err(err) if disposition == requests::Disposition::Open || disposition == requests::Disposition::TruncateExisting => return Err(err),
+				Err(_) => {
+					let result = self.client.create_directory(file_name.clone(), DEFAULT_DIRECTORY_MODE, true, false);
+					self.trigger_update(&file_name);
+					client::convert_response(result)?;
+					true
+				}
+			};
+
+			return Ok(CreateFileInfo {
 				context: 0,
 				is_dir: true,
-				new_file_created: true
+				new_file_created: new_file_created
 			})
 		}
+
+		if existing.is_ok() && create_options & FILE_DIRECTORY_FILE == FILE_DIRECTORY_FILE {
+			return Err(OperationError::NtStatus(STATUS_NOT_A_DIRECTORY));
+		}
+
+		let access = convert_access(desired_access, disposition);
+		let result = self.client.open_file(file_name.clone(), access, disposition, DEFAULT_FILE_MODE, false, false);
+		if disposition != requests::Disposition::Open {
+			self.trigger_update(&file_name);
+		}
+		let handle = client::convert_response(result)?;
+
+		Ok(CreateFileInfo {
+			context: handle,
+			is_dir: false,
+			new_file_created: existing.is_err() && disposition != requests::Disposition::Open
+		})
 	}
 
     fn close_file(
@@ -150,25 +469,31 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 
     fn write_file(
 		&'b self,
-		_: &U16CStr,
+		win_file_name: &U16CStr,
 		offset: i64,
 		buffer: &[u8],
 		_info: &OperationInfo<'a, 'b, Self>,
 		context: &'a Self::Context,
 	) -> Result<u32, OperationError> {
-		client::convert_response(self.client.write_file(*context, offset as u64, buffer))?;
-
-		Ok(buffer.len() as u32)
+		let file_name = self.convert_file_name(win_file_name);
+		let result = self.client.write_file(*context, offset as u64, buffer);
+		// The file's size and modification time have changed, which the cached stat and listing would otherwise hide
+		self.trigger_update(&file_name);
+		client::convert_response(result)
 	}
 
     fn flush_file_buffers(
 		&'b self,
 		_file_name: &U16CStr,
 		_info: &OperationInfo<'a, 'b, Self>,
-		_context: &'a Self::Context,
+		context: &'a Self::Context,
 	) -> Result<(), OperationError> {
-		// TODO: Keep some written data in a buffer and flush here?
-		Ok(())
+		// Directories are not opened on the server, so there is nothing to flush
+		if *context == 0 {
+			return Ok(());
+		}
+
+		client::convert_response(self.client.flush(*context, false))
 	}
 
     fn get_file_information(
@@ -177,7 +502,7 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 		_info: &OperationInfo<'a, 'b, Self>,
 		_context: &'a Self::Context,
 	) -> Result<FileInfo, OperationError> {
-		let file_name = convert_file_name(win_file_name);
+		let file_name = self.convert_file_name(win_file_name);
 		let file_info = self.stat_file(file_name)?;
 
 		Ok(FileInfo {
@@ -186,7 +511,7 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 			last_access_time: file_info.last_accessed,
 			last_write_time: file_info.last_modified,
 			file_size: file_info.size,
-			number_of_links: 0, // TODO: Find a way to fetch this data?
+			number_of_links: file_info.nlink as u32,
 			file_index: file_info.ino
 		})
 	}
@@ -198,26 +523,29 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 		_info: &OperationInfo<'a, 'b, Self>,
 		_context: &'a Self::Context,
 	) -> Result<(), OperationError> {
-		let file_name = convert_file_name(win_file_name);
+		let file_name = self.convert_file_name(win_file_name);
 
-		let files: Vec<models::FileInfo> = match self.directory_cache.try_get(&file_name) {
-			Some(files) => files,
+		let listing = match self.directory_cache.try_get(&file_name) {
+			Some(listing) => listing?,
 			None => {
-				let result = client::convert_response(self.client.list_files(file_name.as_str()));
-				self.directory_cache.put(file_name.clone(), result.clone());
-				result
+				let stale = match find_stale(&self.directory_cache, &file_name) {
+					Some(stale) => stale,
+					None => return self.find_files_uncached(file_name, fill_find_data)
+				};
+
+				// Checking an expired listing only costs a few bytes, and a listing that has changed is read again a page at a time
+				match client::convert_response(self.client.list_token(&file_name, true)) {
+					Ok(token) if stale.token == Some(token) => {
+						self.cache_listing(file_name, Ok(stale.clone()));
+						stale
+					},
+					_ => return self.find_files_uncached(file_name, fill_find_data)
+				}
 			}
-		}?;
-
-		for file in &files {
-			fill_find_data.call_mut((&FindData {
-				attributes: convert_attributes(file),
-				creation_time: file.creation_time,
-				last_access_time: file.last_accessed,
-				last_write_time: file.last_modified,
-				file_size: file.size,
-				file_name: U16CString::from_str(file.name.as_str()).unwrap()
-			},))?;
+		};
+
+		for file in &listing.value.files {
+			fill_find_data.call_mut((&convert_find_data(file),))?;
 		}
 
 		Ok(())
@@ -235,15 +563,30 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 
     fn set_file_time(
 		&'b self,
-		_file_name: &U16CStr,
+		win_file_name: &U16CStr,
 		_creation_time: FileTimeInfo,
-		_last_access_time: FileTimeInfo,
-		_last_write_time: FileTimeInfo,
+		last_access_time: FileTimeInfo,
+		last_write_time: FileTimeInfo,
 		_info: &OperationInfo<'a, 'b, Self>,
-		_context: &'a Self::Context,
+		context: &'a Self::Context,
 	) -> Result<(), OperationError> {
-		// TODO?: Is there even any point to this
-		Ok(())
+		// Linux has no way to set the creation time, so it is ignored
+		let accessed = convert_file_time(last_access_time);
+		let modified = convert_file_time(last_write_time);
+		if accessed.is_none() && modified.is_none() {
+			return Ok(());
+		}
+
+		let file_name = self.convert_file_name(win_file_name);
+		// Directories are not opened on the server, so have no handle
+		let target = match *context {
+			0 => requests::FileTarget::Path(file_name.clone()),
+			handle => requests::FileTarget::Handle(handle)
+		};
+
+		let result = self.client.set_times(target, accessed, modified);
+		self.trigger_update(&file_name);
+		client::convert_response(result)
 	}
 
     fn delete_file(
@@ -254,7 +597,7 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 	) -> Result<(), OperationError> {
 		// TODO: Never called, likely due to incomplete create_file
 
-		let file_name = convert_file_name(win_file_name);
+		let file_name = self.convert_file_name(win_file_name);
 		self.trigger_update(&file_name);
 
 		client::convert_response(self.client.delete_file(file_name))
@@ -268,7 +611,7 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 	) -> Result<(), OperationError> {
 		// TODO: Never called, likely due to incomplete open_file
 
-		let file_name = convert_file_name(win_file_name);
+		let file_name = self.convert_file_name(win_file_name);
 		self.trigger_update(&file_name);
 
 		client::convert_response(self.client.delete_file(file_name))
@@ -282,8 +625,8 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 		_info: &OperationInfo<'a, 'b, Self>,
 		_context: &'a Self::Context,
 	) -> Result<(), OperationError> {
-		let from = convert_file_name(file_name);
-		let to = convert_file_name(new_file_name);
+		let from = self.convert_file_name(file_name);
+		let to = self.convert_file_name(new_file_name);
 
 		self.trigger_update(&from);
 		self.trigger_update(&to);
@@ -297,12 +640,15 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 
     fn set_end_of_file(
 		&'b self,
-		_file_name: &U16CStr,
+		win_file_name: &U16CStr,
 		offset: i64,
 		_info: &OperationInfo<'a, 'b, Self>,
 		context: &'a Self::Context,
 	) -> Result<(), OperationError> {
-		client::convert_response(self.client.set_end_of_file(*context, offset as u64))
+		let file_name = self.convert_file_name(win_file_name);
+		let result = self.client.set_end_of_file(*context, offset as u64);
+		self.trigger_update(&file_name);
+		client::convert_response(result)
 	}
 
     fn set_allocation_size(
@@ -319,12 +665,12 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 		&'b self,
 		_info: &OperationInfo<'a, 'b, Self>,
 	) -> Result<DiskSpaceInfo, OperationError> {
-		let free_space = client::convert_response(self.client.get_free_space())?;
+		let stat = client::convert_response(self.client.stat_fs(&self.root))?;
 
 		Ok(DiskSpaceInfo {
-			byte_count: free_space.total_bytes,
-			free_byte_count: free_space.free_bytes,
-			available_byte_count: free_space.free_bytes
+			byte_count: stat.total_bytes,
+			free_byte_count: stat.free_bytes,
+			available_byte_count: stat.available_bytes
 		})
 	}
 
@@ -332,12 +678,17 @@ impl<'a, 'b: 'a> FileSystemHandler<'a, 'b> for QuestFsHandler {
 		&'b self,
 		_info: &OperationInfo<'a, 'b, Self>,
 	) -> Result<VolumeInfo, OperationError> {
+		let mut fs_flags = winnt::FILE_CASE_PRESERVED_NAMES | winnt::FILE_CASE_SENSITIVE_SEARCH | winnt::FILE_UNICODE_ON_DISK
+			| winnt::FILE_PERSISTENT_ACLS | winnt::FILE_NAMED_STREAMS;
+		if self.client.is_read_only() {
+			fs_flags |= winnt::FILE_READ_ONLY_VOLUME;
+		}
+
 		Ok(VolumeInfo {
                 name: self.volume_name.clone(),
                 serial_number: 0,
                 max_component_length: 4095, // Path length limit on Android
-                fs_flags: winnt::FILE_CASE_PRESERVED_NAMES | winnt::FILE_CASE_SENSITIVE_SEARCH | winnt::FILE_UNICODE_ON_DISK
-				| winnt::FILE_PERSISTENT_ACLS | winnt::FILE_NAMED_STREAMS,
+                fs_flags: fs_flags,
                 fs_name: U16CString::from_str("NTFS").unwrap() // Windows will recognise NTFS and turn on all features
         })
 	}
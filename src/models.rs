@@ -2,6 +2,10 @@ use std::time::SystemTime;
 use crate::serde::{Serialize, Deserialize};
 
 pub type FileHandle = u32;
+// Identifies a directory opened with OpenDirectory, separately from file handles
+pub type DirectoryHandle = u32;
+// Identifies a version of a listing or stat, so that it only needs to be sent again once it has changed
+pub type ChangeToken = u64;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FileInfo {
@@ -11,5 +15,26 @@ pub struct FileInfo {
     pub name: String,
     pub size: u64,
     pub mode: u32,
-    pub ino: u64
+    pub ino: u64,
+    // Set if the entry is a symlink, in which case the other fields describe the link itself unless it was followed
+    pub link: Option<LinkInfo>,
+    // Number of hard links to the file
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    // ID of the device containing the file, which together with ino identifies it
+    pub dev: u64,
+    // Number of 512 byte blocks allocated to the file
+    pub blocks: u64,
+    // Names of the user and group that own the file, or None if they have no name
+    pub owner: Option<String>,
+    pub group: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LinkInfo {
+    // Path the link points to, exactly as stored in the link
+    pub target: String,
+    // Mode of the file the link points to, or None if the link is broken or points outside of the allowed roots
+    pub target_mode: Option<u32>
 }
\ No newline at end of file
@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Storage volumes are mounted directly within this directory, e.g. /storage/emulated or /storage/<UUID>
+pub const STORAGE_DIRECTORY: &str = "/storage";
+// Name of the internal storage's mount point within STORAGE_DIRECTORY
+pub const INTERNAL_STORAGE_NAME: &str = "emulated";
+// Name within STORAGE_DIRECTORY that only links to the current user's internal storage, so is not a volume of its own
+pub const SELF_STORAGE_NAME: &str = "self";
+// Links to the current user's directory within the internal storage, e.g. /storage/emulated/0
+// The internal storage's mount point itself cannot be listed by the shell user
+pub const SELF_PRIMARY_PATH: &str = "/storage/self/primary";
+// Removable volumes are mounted from their block device within this directory, then made visible within STORAGE_DIRECTORY under the same name
+const MEDIA_DIRECTORY: &str = "/mnt/media_rw";
+// Major number of MMC block devices, which SD cards are
+const MMC_BLOCK_MAJOR: u32 = 179;
+
+pub struct Mount {
+    pub source: String,
+    pub mount_point: PathBuf,
+    pub filesystem_type: String
+}
+
+// Reads the mount table of the server's process
+pub fn read_mounts() -> std::io::Result<Vec<Mount>> {
+    let table = fs::read_to_string("/proc/mounts")?;
+
+    Ok(table.lines().filter_map(|line| {
+        // Fields are the source, mount point, filesystem type, options and two unused numbers
+        let mut fields = line.split(' ');
+        Some(Mount {
+            source: unescape(fields.next()?),
+            mount_point: PathBuf::from(unescape(fields.next()?)),
+            filesystem_type: unescape(fields.next()?)
+        })
+    }).collect())
+}
+
+// Finds the mount that contains path, which must be canonicalized
+// Later mounts are mounted over earlier ones, so the last of the longest mount points wins
+pub fn find_mount<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Mount> {
+    mounts.iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .fold(None, |found: Option<&Mount>, mount| match found {
+            Some(found) if found.mount_point.components().count() > mount.mount_point.components().count() => Some(found),
+            _ => Some(mount)
+        })
+}
+
+// Names a removable volume after the kind of device it is on, as its filesystem label cannot be read without root
+pub fn describe_removable(mounts: &[Mount], name: &str) -> &'static str {
+    let media_path = Path::new(MEDIA_DIRECTORY).join(name);
+    let device = mounts.iter().rev()
+        .find(|mount| mount.mount_point == media_path)
+        .and_then(|mount| parse_block_device(&mount.source));
+
+    match device {
+        Some((MMC_BLOCK_MAJOR, _)) => "SD card",
+        Some((major, minor)) => match fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)) {
+            Ok(device_path) if device_path.to_string_lossy().contains("/usb") => "USB drive",
+            _ => "Removable storage"
+        },
+        None => "Removable storage"
+    }
+}
+
+// Vold names the block devices of removable volumes after their device numbers, e.g. /dev/block/vold/public:179,1
+pub fn parse_block_device(source: &str) -> Option<(u32, u32)> {
+    let (_, numbers) = source.rsplit_once(':')?;
+    let (major, minor) = numbers.split_once(',')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+// Spaces, tabs, newlines and backslashes within fields of /proc/mounts are escaped as octal, e.g. \040 for a space
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+
+        match escape {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            },
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
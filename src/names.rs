@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::Mutex;
+
+// Size of the buffer given to getpwuid_r and getgrgid_r for the strings they return
+const BUFFER_SIZE: usize = 4096;
+
+// Resolves user and group IDs to names, remembering the result since they rarely change and are looked up for every file listed
+pub struct NameCache {
+    users: Mutex<HashMap<u32, Option<String>>>,
+    groups: Mutex<HashMap<u32, Option<String>>>
+}
+
+impl NameCache {
+    pub fn new() -> NameCache {
+        NameCache {
+            users: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new())
+        }
+    }
+
+    // Returns None if the user has no name
+    pub fn user_name(&self, uid: u32) -> Option<String> {
+        self.users.lock().unwrap()
+            .entry(uid)
+            .or_insert_with(|| lookup_user(uid))
+            .clone()
+    }
+
+    // Returns None if the group has no name
+    pub fn group_name(&self, gid: u32) -> Option<String> {
+        self.groups.lock().unwrap()
+            .entry(gid)
+            .or_insert_with(|| lookup_group(gid))
+            .clone()
+    }
+}
+
+fn lookup_user(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let err = unsafe { libc::getpwuid_r(uid as libc::uid_t, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if err != 0 || result.is_null() || passwd.pw_name.is_null() {
+        return None;
+    }
+
+    Some(unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().to_string())
+}
+
+fn lookup_group(gid: u32) -> Option<String> {
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+
+    let err = unsafe { libc::getgrgid_r(gid as libc::gid_t, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if err != 0 || result.is_null() || group.gr_name.is_null() {
+        return None;
+    }
+
+    Some(unsafe { CStr::from_ptr(group.gr_name) }.to_string_lossy().to_string())
+}
@@ -0,0 +1,105 @@
+use std::str::FromStr;
+use std::time::Duration;
+use log::LevelFilter;
+
+pub const USAGE: &str = "Usage: androidfs_server [OPTIONS]
+
+Options:
+    --address <ADDRESS>        Address to listen on [default: 127.0.0.1]
+    --port <PORT>              Port to listen on [default: 12345]
+    --root <PATH>              Only allow clients to access paths within PATH. May be given multiple times [default: /]
+    --read-only                Reject any modifications, regardless of what clients request
+    --idle-timeout <SECONDS>   Stop once no clients have been connected for SECONDS, or never if 0 [default: 60]
+    --storage-mount <PATH>     Mount point of the storage to report free space for [default: /storage/emulated]
+    --log-level <LEVEL>        One of off, error, warn, info, debug or trace [default: info]
+    --log-file <PATH>          Append log output to PATH instead of writing it to stderr
+    --pid-file <PATH>          Write the process ID to PATH once listening, so that the server can be stopped later
+    --token-file <PATH>        Read the token that clients will authenticate with from PATH, then delete PATH
+    -h, --help                 Print this message
+    -V, --version              Print the version
+
+Unless --token-file is given, the ANDROIDFS_TOKEN environment variable must be set to the token that clients will authenticate with.";
+
+pub struct Options {
+    pub address: String,
+    pub port: u16,
+    pub roots: Vec<String>,
+    pub read_only: bool,
+    // None if the server should never stop due to being idle
+    pub idle_timeout: Option<Duration>,
+    pub storage_mount: String,
+    pub log_level: LevelFilter,
+    pub log_file: Option<String>,
+    pub pid_file: Option<String>,
+    pub token_file: Option<String>
+}
+
+pub enum Action {
+    Run(Options),
+    PrintHelp,
+    PrintVersion
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            address: "127.0.0.1".to_string(),
+            port: 12345,
+            roots: Vec::new(),
+            read_only: false,
+            idle_timeout: Some(Duration::from_secs(60)),
+            storage_mount: "/storage/emulated".to_string(),
+            log_level: LevelFilter::Info,
+            log_file: None,
+            pid_file: None,
+            token_file: None
+        }
+    }
+}
+
+// Parses the arguments passed to the server, excluding the executable name
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Action, String> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Action::PrintHelp),
+            "-V" | "--version" => return Ok(Action::PrintVersion),
+            "--address" => options.address = next_value(&mut args, &arg)?,
+            "--port" => options.port = parse_value(&mut args, &arg)?,
+            "--root" => options.roots.push(next_value(&mut args, &arg)?),
+            "--read-only" => options.read_only = true,
+            "--idle-timeout" => options.idle_timeout = match parse_value(&mut args, &arg)? {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds))
+            },
+            "--storage-mount" => options.storage_mount = next_value(&mut args, &arg)?,
+            "--log-level" => options.log_level = parse_value(&mut args, &arg)?,
+            "--log-file" => options.log_file = Some(next_value(&mut args, &arg)?),
+            "--pid-file" => options.pid_file = Some(next_value(&mut args, &arg)?),
+            "--token-file" => options.token_file = Some(next_value(&mut args, &arg)?),
+            _ => return Err(format!("Unknown argument {}", arg))
+        }
+    }
+
+    if options.roots.is_empty() {
+        options.roots.push("/".to_string());
+    }
+
+    Ok(Action::Run(options))
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, arg: &str) -> Result<String, String> {
+    match args.next() {
+        Some(value) => Ok(value),
+        None => Err(format!("Missing value for {}", arg))
+    }
+}
+
+fn parse_value<T: FromStr>(args: &mut impl Iterator<Item = String>, arg: &str) -> Result<T, String> {
+    let value = next_value(args, arg)?;
+    match value.parse() {
+        Ok(parsed) => Ok(parsed),
+        Err(_) => Err(format!("Invalid value {} for {}", value, arg))
+    }
+}
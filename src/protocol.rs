@@ -0,0 +1,104 @@
+use std::io::{Read, Write};
+use std::convert::TryInto;
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use crate::serde::{Serialize, de::DeserializeOwned};
+use bincode::Options;
+
+// Must be incremented whenever the layout of requests, responses or models changes
+pub const PROTOCOL_VERSION: u32 = 23;
+
+// Largest message that will be accepted, to avoid allocating huge buffers for malformed lengths
+pub const MAX_MESSAGE_LENGTH: u64 = 16 * 1024 * 1024;
+
+// Largest frame that will be accepted. Frames are only used for hellos, which are read before the other side has authenticated
+pub const MAX_FRAME_LENGTH: u64 = 4 * 1024;
+
+// Tags each request and response so that responses can be sent out of order
+pub type RequestId = u32;
+
+// Reported to the other side during the handshake to make mismatched installs easier to diagnose
+// Set by build.rs to the version, commit and time of the build, so it differs between any two builds
+pub const BUILD_ID: &str = env!("ANDROIDFS_BUILD_ID");
+
+// Deserializes a frame or message payload, refusing to allocate more than MAX_MESSAGE_LENGTH while doing so
+// Uses the same encoding as bincode::serialize
+pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_LENGTH)
+        .deserialize(bytes)
+}
+
+fn check_length(length: u64, max_length: u64) -> std::io::Result<()> {
+    if length > max_length {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Length {} exceeds the maximum of {}", length, max_length)));
+    }
+
+    Ok(())
+}
+
+// Writes a value as a bincode frame prefixed with its length
+pub fn write_frame<T: Serialize>(stream: &mut impl Write, value: &T) -> bincode::Result<()> {
+    let encoded = bincode::serialize(value)?;
+    stream.write_u64::<BigEndian>(encoded.len().try_into().unwrap())?;
+    stream.write_all(&encoded[..])?;
+    Ok(())
+}
+
+// Reads a length prefixed frame written by write_frame, returning its still encoded payload
+// Hellos are decoded in two steps, reading only the leading protocol version until it is known to match
+pub fn read_frame(stream: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let length = stream.read_u64::<BigEndian>()?;
+    check_length(length, MAX_FRAME_LENGTH)?;
+    let mut buffer = vec![0u8; length as usize];
+    stream.read_exact(&mut buffer[..])?;
+
+    Ok(buffer)
+}
+
+// Encodes the payload of a message, failing if the message would be longer than the other side accepts
+pub fn serialize_payload<T: Serialize>(value: &T) -> bincode::Result<Vec<u8>> {
+    let encoded = bincode::serialize(value)?;
+    if 4 + encoded.len() as u64 > MAX_MESSAGE_LENGTH {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+
+    Ok(encoded)
+}
+
+// Writes a message tagged with the ID of the request it belongs to
+// The whole message is written at once, so messages from different threads cannot interleave
+// Nothing is written if the value could not be encoded
+pub fn write_message<T: Serialize>(stream: &mut impl Write, request_id: RequestId, value: &T) -> bincode::Result<()> {
+    let encoded = serialize_payload(value)?;
+    write_encoded_message(stream, request_id, &encoded[..])?;
+    Ok(())
+}
+
+// Writes a message whose payload has already been encoded with serialize_payload
+pub fn write_encoded_message(stream: &mut impl Write, request_id: RequestId, encoded: &[u8]) -> std::io::Result<()> {
+    // The other side would close the connection on receiving a longer message
+    check_length(4 + encoded.len() as u64, MAX_MESSAGE_LENGTH)?;
+    let mut message = Vec::with_capacity(12 + encoded.len());
+    message.write_u64::<BigEndian>((4 + encoded.len()).try_into().unwrap())?;
+    message.write_u32::<BigEndian>(request_id)?;
+    message.extend_from_slice(encoded);
+
+    stream.write_all(&message[..])
+}
+
+// Reads a message written by write_message, returning its request ID and still encoded payload
+pub fn read_message(stream: &mut impl Read) -> std::io::Result<(RequestId, Vec<u8>)> {
+    let length = stream.read_u64::<BigEndian>()?;
+    check_length(length, MAX_MESSAGE_LENGTH)?;
+    if length < 4 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Message too short to contain a request ID"));
+    }
+
+    let request_id = stream.read_u32::<BigEndian>()?;
+    let mut payload = vec![0u8; (length - 4) as usize];
+    stream.read_exact(&mut payload[..])?;
+
+    Ok((request_id, payload))
+}
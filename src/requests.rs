@@ -1,3 +1,4 @@
+use std::time::SystemTime;
 use crate::serde::{Serialize, Deserialize};
 use crate::models::*;
 
@@ -14,7 +15,39 @@ pub enum Request {
     GetFreeSpace,
     Read(ReadFile),
     Write(WriteFile),
-    SetEndOfFile(SetEndOfFile)
+    SetEndOfFile(SetEndOfFile),
+    SetTimes(SetTimes),
+    SetMode(SetMode),
+    SetOwner(SetOwner),
+    ReadLink(ReadLink),
+    CreateSymlink(CreateSymlink),
+    Flush(Flush),
+    SyncFilesystem(SyncFilesystem),
+    StatFs(StatFs),
+    ListVolumes,
+    Watch(Watch),
+    Unwatch(Unwatch),
+    OpenDirectory(OpenDirectory),
+    ReadDirectory(ReadDirectory),
+    CloseDirectory(CloseDirectory),
+    StatMany(StatMany),
+    // Returns only the token of a directory's listing, to check whether a cached listing is still up to date
+    ListToken(ListToken),
+    // Handles several requests in one round trip
+    Batch(Batch),
+    // Stops the server, disconnecting every client. Rejected in read-only sessions
+    Shutdown
+}
+
+// Sent by the client as the first frame of every connection, before any Request
+// protocol_version must stay the first field so that it can be read by any version of the server
+#[derive(Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    // Secret given to the server when it was started
+    pub token: String,
+    // Requests that the server rejects any modifications made through this connection
+    pub read_only: bool
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,7 +61,7 @@ pub struct ReadFile {
 pub struct WriteFile {
     pub handle: FileHandle,
     pub offset: u64,
-    pub len: u64
+    pub data: Vec<u8>
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,30 +70,183 @@ pub struct SetEndOfFile {
     pub len: u64
 }
 
+// The file that a request acts on, given either by path or by a handle from OpenFile
+#[derive(Serialize, Deserialize)]
+pub enum FileTarget {
+    Path(String),
+    Handle(FileHandle)
+}
+
+// Timestamps that are None are left unchanged
+#[derive(Serialize, Deserialize)]
+pub struct SetTimes {
+    pub target: FileTarget,
+    pub accessed: Option<SystemTime>,
+    pub modified: Option<SystemTime>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetMode {
+    pub target: FileTarget,
+    // Permission bits, including the setuid, setgid and sticky bits
+    pub mode: u32
+}
+
+// IDs that are None are left unchanged
+#[derive(Serialize, Deserialize)]
+pub struct SetOwner {
+    pub target: FileTarget,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>
+}
+
+// Waits until everything written through the handle has reached the disk
+#[derive(Serialize, Deserialize)]
+pub struct Flush {
+    pub handle: FileHandle,
+    // If true, metadata that is not needed to read the data back, such as timestamps, may not be synced
+    pub data_only: bool
+}
+
+// Path of any file within the filesystem to sync, or None to sync every filesystem
+pub type SyncFilesystem = Option<String>;
+
+// Reports changes to a file, or to the entries of a directory, until Unwatch is sent
+// Changes are sent as responses to the Watch request, starting with WatchEvent::Started once the watch has been set up
+#[derive(Serialize, Deserialize)]
+pub struct Watch {
+    pub path: String,
+    // If true, changes within subdirectories are also reported
+    pub recursive: bool
+}
+
+// ID of the Watch request to stop
+pub type Unwatch = u32;
+
 pub type CloseFile = FileHandle;
 
-pub type ListFiles = String;
+#[derive(Serialize, Deserialize)]
+pub struct ListFiles {
+    pub path: String,
+    // If true, entries that are symlinks are described by the files they point to, where those can be followed
+    pub follow_symlinks: bool,
+    // Token of a listing that the client already has, in which case NotModified is returned if it is still up to date
+    pub if_changed: Option<ChangeToken>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListToken {
+    pub path: String,
+    pub follow_symlinks: bool
+}
+
+// Starts reading the entries of a directory, which are then returned a page at a time by ReadDirectory
+#[derive(Serialize, Deserialize)]
+pub struct OpenDirectory {
+    pub path: String,
+    // If true, entries that are symlinks are described by the files they point to, where those can be followed
+    pub follow_symlinks: bool
+}
+
+// Returns the next entries of a directory, continuing from where the last ReadDirectory for the handle stopped
+#[derive(Serialize, Deserialize)]
+pub struct ReadDirectory {
+    pub handle: DirectoryHandle,
+    // The server may return fewer entries than this, even if the end of the directory has not been reached
+    pub max_entries: u32
+}
+
+pub type CloseDirectory = DirectoryHandle;
+
+#[derive(Serialize, Deserialize)]
+pub struct StatFile {
+    pub path: String,
+    // If true, a symlink is described by the file it points to, where that can be followed
+    pub follow_symlinks: bool,
+    // Token of a stat that the client already has, in which case NotModified is returned if it is still up to date
+    pub if_changed: Option<ChangeToken>
+}
 
-pub type StatFile = String;
+// Stats several files at once, failing separately for each one that cannot be stat-ed
+#[derive(Serialize, Deserialize)]
+pub struct StatMany {
+    pub paths: Vec<String>,
+    // If true, symlinks are described by the files they point to, where those can be followed
+    pub follow_symlinks: bool
+}
+
+// Only requests that query metadata can be batched, any others fail with InvalidRequest
+pub type Batch = Vec<Request>;
+
+pub type ReadLink = String;
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSymlink {
+    // Where the link is created
+    pub path: String,
+    // Stored in the link as is, so may be relative to the directory containing it
+    pub target: String
+}
 
 pub type DeleteFile = String;
 
+// Path of any file within the filesystem to describe
+pub type StatFs = String;
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateFile {
-    path: String,
-    // TODO: Additional details
+    pub path: String,
+    // Permission bits of the new file, before the umask is applied
+    pub mode: u32,
+    // If true, fails with FileExists if the file already exists, otherwise an existing file is left as it is
+    pub exclusive: bool,
+    // If true, any missing parent directories are also created
+    pub create_parents: bool
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateDirectory {
-    path: String,
-    // TODO: Additional details
+    pub path: String,
+    // Permission bits of the new directory, before the umask is applied
+    pub mode: u32,
+    // If true, fails with FileExists if the directory already exists
+    pub exclusive: bool,
+    // If true, any missing parent directories are also created
+    pub create_parents: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct AccessMode {
+    pub read: bool,
+    pub write: bool,
+    // Every write goes to the end of the file, regardless of its offset
+    pub append: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Disposition {
+    // Fails with FileNotFound if the file does not exist
+    Open,
+    // Fails with FileExists if the file already exists
+    CreateNew,
+    OpenOrCreate,
+    // Empties the file, failing with FileNotFound if it does not exist
+    TruncateExisting,
+    // Empties the file, or creates it if it does not exist
+    Supersede
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct OpenFile {
     pub path: String,
-    // TODO: Additional details
+    pub access: AccessMode,
+    pub disposition: Disposition,
+    // Permission bits of the file if it is created, before the umask is applied
+    pub mode: u32,
+    // If true, fails instead of following a symlink in the last component of the path
+    pub no_follow: bool,
+    // If true, fails with NotADirectory unless the path is a directory
+    pub directory_only: bool
 }
 
 #[derive(Serialize, Deserialize)]
@@ -3,9 +3,67 @@ use crate::models::*;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub type ListFiles = Vec<FileInfo>;
-pub type StatFile = FileInfo;
-pub type ReadFile = u32;
+pub type ListFiles = Conditional<Vec<FileInfo>>;
+pub type StatFile = Conditional<FileInfo>;
+// Same as the token of a listing of the directory, including one read a page at a time
+pub type ListToken = ChangeToken;
+pub type OpenDirectory = DirectoryHandle;
+// Result for each path, in the order they were given
+pub type StatMany = Vec<Result<FileInfo>>;
+// Response to each request, in the order they were given
+pub type Batch = Vec<BatchResponse>;
+// Number of bytes written. If writing fails part way through, the error is returned instead
+pub type WriteFile = u64;
+// Target of the link, exactly as stored in it
+pub type ReadLink = String;
+
+// Sent by the server in reply to requests::Hello
+// protocol_version must stay the first field so that it can be read by any version of the client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub build_id: String,
+    pub capabilities: Vec<String>,
+    // False if the client presented the wrong token, in which case the server closes the connection
+    pub authenticated: bool,
+    // True if the server will reject any modifications made through this connection
+    pub read_only: bool
+}
+
+// Reply to a request that only returns a value if it no longer has the token given by the client
+#[derive(Serialize, Deserialize)]
+pub enum Conditional<T> {
+    Modified {
+        value: T,
+        // Given in later requests to check whether value is still up to date
+        token: ChangeToken
+    },
+    NotModified
+}
+
+// Response to one of the requests in a Batch, which is the same as the request would get on its own
+#[derive(Serialize, Deserialize)]
+pub enum BatchResponse {
+    List(Result<ListFiles>),
+    Stat(Result<StatFile>),
+    StatMany(Result<StatMany>),
+    ListToken(Result<ListToken>),
+    ReadLink(Result<ReadLink>),
+    GetFreeSpace(Result<FreeSpace>),
+    StatFs(Result<StatFs>),
+    ListVolumes(Result<ListVolumes>),
+    // The request cannot be batched, so was not handled
+    Invalid(Error)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReadDirectory {
+    pub entries: Vec<FileInfo>,
+    // True once every entry of the directory has been returned, after which the handle should be closed
+    pub end: bool,
+    // Set on the last page, to the token that ListFiles gives the whole listing
+    pub token: Option<ChangeToken>
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct FreeSpace {
@@ -13,12 +71,127 @@ pub struct FreeSpace {
     pub free_bytes: u64
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct StatFs {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    // Free bytes that can be used by unprivileged processes, which excludes any space reserved for root
+    pub available_bytes: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub block_size: u64,
+    // Type given in the mount table, e.g. ext4 or sdcardfs, or None if the mount could not be found
+    pub filesystem_type: Option<String>,
+    pub read_only: bool
+}
+
+pub type ListVolumes = Vec<Volume>;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Volume {
+    // Where the volume is mounted, e.g. /storage/emulated/0 for the current user's internal storage or /storage/1234-ABCD for an SD card
+    pub path: String,
+    // Name to show for the volume, e.g. "Internal storage", "SD card" or "USB drive"
+    pub label: String,
+    // True for SD cards and USB drives
+    pub removable: bool,
+    pub filesystem_type: String,
+    pub total_bytes: u64,
+    // Free bytes that can be used by unprivileged processes
+    pub free_bytes: u64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum WatchEvent {
+    // Sent once the watch has been set up, before any changes
+    Started,
+    Changed(ChangeEvent),
+    // Some changes were not reported, so anything within the watched path may have changed
+    Overflowed,
+    // Sent once the watch has been stopped by Unwatch, or because the watched path was deleted. Nothing further is sent
+    Stopped
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangeEvent {
+    // Within the path that was watched, e.g. /sdcard/a/b if /sdcard was watched recursively
+    pub path: String,
+    pub kind: ChangeKind
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ChangeKind {
+    Created,
+    Deleted,
+    // The contents or metadata of the file changed
+    Modified,
+    // The file was moved to path from within the watched path
+    Renamed {
+        from: String
+    }
+}
+
+// Reads are answered with any number of Data chunks, followed by End with the total length read
+// If the read fails, an error is sent instead of End
+#[derive(Serialize, Deserialize)]
+pub enum ReadChunk {
+    Data(Vec<u8>),
+    End(u64)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Error {
+pub struct Error {
+    pub kind: ErrorKind,
+    // The errno that caused the error, if it came from the OS
+    pub errno: Option<i32>,
+    pub message: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
     FileNotFound,
     NoSuchHandle,
     FileExists,
     PermissionDenied,
     CouldNotFindDisk,
+    // The device has no space left to write to
+    NoSpace,
+    // The request would modify files, but the connection is read-only
+    ReadOnly,
+    // The request could not be deserialized
+    InvalidRequest,
+    // The server does not implement the request
+    NotSupported,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    // The filesystem itself is mounted read-only on the device
+    ReadOnlyFilesystem,
+    NameTooLong,
+    // Moving a file between filesystems is not supported
+    CrossDevice,
+    Busy,
+    TooManyOpenFiles,
+    Interrupted,
     Other
-}
\ No newline at end of file
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            errno: None,
+            message: String::new()
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self.kind))?;
+        if !self.message.is_empty() {
+            f.write_fmt(format_args!(": {}", self.message))?;
+        }
+        Ok(())
+    }
+}
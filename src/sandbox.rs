@@ -0,0 +1,88 @@
+use std::fs;
+use std::path::{Path, PathBuf, Component};
+
+use crate::responses;
+use super::to_response_error;
+
+// Restricts the paths that clients can access to within a set of allowed roots
+pub struct Sandbox {
+    roots: Vec<PathBuf>
+}
+
+impl Sandbox {
+    pub fn new(roots: &[String]) -> std::io::Result<Sandbox> {
+        // Canonicalized so that they can be compared against canonicalized paths
+        let roots = roots.iter()
+            .map(fs::canonicalize)
+            .collect::<std::io::Result<Vec<PathBuf>>>()?;
+
+        Ok(Sandbox {
+            roots
+        })
+    }
+
+    // Resolves a path to the file it points to, following symlinks in every component
+    // Used for operations that act on the target of a symlink, e.g. opening or listing
+    pub fn resolve(&self, path: &str) -> responses::Result<PathBuf> {
+        let path = Path::new(path);
+        if !path.is_absolute() {
+            return Err(responses::ErrorKind::PermissionDenied.into());
+        }
+
+        match fs::canonicalize(path) {
+            Ok(canonical) => self.check(canonical),
+            Err(err) => Err(to_response_error(err))
+        }
+    }
+
+    // Resolves a path without following a symlink in its last component. The path and its parents do not need to exist
+    // Used for operations that act on the entry itself, e.g. deleting or moving, or that create it
+    pub fn resolve_entry(&self, path: &str) -> responses::Result<PathBuf> {
+        let path = Path::new(path);
+        if !path.is_absolute() {
+            return Err(responses::ErrorKind::PermissionDenied.into());
+        }
+
+        // Paths such as / or /a/.. have no last component to leave unresolved
+        let (mut parent, file_name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => (parent, file_name),
+            _ => return self.resolve(&path.to_string_lossy())
+        };
+
+        // Only the closest existing ancestor can be canonicalized, the missing directories are appended to it
+        let mut missing = Vec::new();
+        while !parent.exists() {
+            match (parent.parent(), parent.components().next_back()) {
+                (Some(grandparent), Some(Component::Normal(name))) => {
+                    missing.push(name);
+                    parent = grandparent;
+                },
+                // A .. within the missing directories cannot be resolved without them existing
+                _ => return Err(responses::ErrorKind::FileNotFound.into())
+            }
+        }
+
+        match fs::canonicalize(parent) {
+            Ok(mut canonical) => {
+                for name in missing.iter().rev() {
+                    canonical.push(name);
+                }
+                canonical.push(file_name);
+                self.check(canonical)
+            },
+            Err(err) => Err(to_response_error(err))
+        }
+    }
+
+    fn check(&self, path: PathBuf) -> responses::Result<PathBuf> {
+        if self.roots.iter().any(|root| path.starts_with(root)) {
+            Ok(path)
+        }   else    {
+            Err(responses::Error {
+                kind: responses::ErrorKind::PermissionDenied,
+                errno: None,
+                message: format!("{} is outside of the allowed roots", path.display())
+            })
+        }
+    }
+}
@@ -1,79 +1,612 @@
 extern crate byteorder;
 extern crate bincode;
-extern crate sysinfo;
 extern crate rand;
 extern crate serde;
+extern crate log;
+extern crate env_logger;
+extern crate libc;
 
 mod requests;
 mod responses;
 mod models;
+mod protocol;
+mod sandbox;
+mod options;
+mod names;
+mod mounts;
+mod watcher;
+mod work_queue;
 use models::*;
+use sandbox::Sandbox;
+use names::NameCache;
+use watcher::Watcher;
+use work_queue::WorkQueue;
+use log::*;
 
 use rand::Rng;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use sysinfo::{SystemExt, DiskExt};
 
-use std::convert::TryInto;
-use std::net::TcpStream;
 use std::{net::TcpListener, io::Read};
 use std::io::{Write, Seek};
 use std::fs;
-use std::time::SystemTime;
-use std::os::unix::fs::MetadataExt;
+use std::time::{SystemTime, Instant, Duration};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, DirBuilderExt, PermissionsExt};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::ffi::CString;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::panic::AssertUnwindSafe;
 
 use serde::Serialize;
 
-type FileHandleMap = HashMap<FileHandle, fs::File>;
+// Each handle is only ever used by one worker at a time, so the files themselves do not need to be locked
+type FileHandleMap = HashMap<FileHandle, Arc<fs::File>>;
+// Likewise for directories, but reading entries advances the directory's position so it is still locked
+type DirectoryHandleMap = HashMap<DirectoryHandle, Arc<Mutex<OpenDirectory>>>;
+
+// How long a newly connected client has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Clients that connect while this many others are still in the handshake are disconnected
+// so that apps without the token cannot use up the server's threads by connecting over and over
+const MAX_PENDING_HANDSHAKES: usize = 16;
+
+// Number of threads handling the requests from a client
+const WORKER_COUNT: usize = 4;
+
+// Maximum length of each chunk of data sent in response to a read
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+// Maximum number of entries returned by each ReadDirectory
+const MAX_DIRECTORY_ENTRIES: u32 = 1024;
+
+// Settings given to the server at startup
+struct Config {
+    // Secret that clients must present during the handshake
+    token: String,
+    sandbox: Sandbox,
+    // If true, every session is read-only regardless of what the client requests
+    read_only: bool,
+    storage_mount: String,
+    names: NameCache
+}
+
+// Used to stop the server once it has been idle for too long
+struct Activity {
+    connected_clients: usize,
+    last_disconnect: Instant
+}
+
+// State shared between the workers handling a client's requests
+struct Session {
+    config: Arc<Config>,
+    // If true, any request that would modify files is rejected
+    read_only: bool,
+    file_handles: Mutex<FileHandleMap>,
+    directory_handles: Mutex<DirectoryHandleMap>,
+    // Flags that stop the threads sending changes for each watch, keyed by the ID of the Watch request
+    watches: Mutex<HashMap<protocol::RequestId, Arc<AtomicBool>>>,
+    writer: Mutex<Box<dyn Write + Send>>
+}
+
+// A directory opened with OpenDirectory, whose entries have been read up to the current position of entries
+struct OpenDirectory {
+    entries: fs::ReadDir,
+    follow_symlinks: bool,
+    // Hash of the entries returned so far, which becomes the listing's token once every entry has been read
+    hasher: DefaultHasher
+}
+
+// How often the threads sending changes for watches check whether they have been stopped
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Reported to clients during the handshake
+const CAPABILITIES: &[&str] = &[
+    "list",
+    "stat",
+    "delete",
+    "create-file",
+    "create-directory",
+    "open",
+    "close",
+    "move",
+    "free-space",
+    "read",
+    "write",
+    "set-end-of-file",
+    "set-times",
+    "set-mode",
+    "set-owner",
+    "read-link",
+    "create-symlink",
+    "flush",
+    "sync-filesystem",
+    "stat-fs",
+    "list-volumes",
+    "watch",
+    "open-directory",
+    "read-directory",
+    "close-directory",
+    "stat-many",
+    "list-token",
+    "batch",
+    "shutdown"
+];
 
 fn main() {
-    println!("Starting up server");
-    let listener = TcpListener::bind("127.0.0.1:12345").unwrap();
-    let (mut client, _) = listener.accept().unwrap();
+    let options = match options::parse(std::env::args().skip(1)) {
+        Ok(options::Action::Run(options)) => options,
+        Ok(options::Action::PrintHelp) => {
+            println!("{}", options::USAGE);
+            return;
+        },
+        Ok(options::Action::PrintVersion) => {
+            println!("androidfs_server {} (protocol version {})", protocol::BUILD_ID, protocol::PROTOCOL_VERSION);
+            return;
+        },
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, options::USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    let mut file_handles = FileHandleMap::new();
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(options.log_level);
+    if let Some(log_file) = &options.log_file {
+        match fs::OpenOptions::new().create(true).append(true).open(log_file) {
+            Ok(file) => logger.target(env_logger::Target::Pipe(Box::new(file))),
+            Err(err) => {
+                eprintln!("Failed to open log file {}: {}", log_file, err);
+                std::process::exit(1);
+            }
+        };
+    }
+    logger.init();
 
-    loop {
-        // Read request length
-        let length = match client.read_u64::<BigEndian>() {
-            Ok(length) => length,
+    info!("Starting up server");
+    info!("Allowed roots: {:?}", options.roots);
+
+    let config = Arc::new(Config {
+        token: match read_token(&options.token_file) {
+            Ok(token) => token,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        sandbox: match Sandbox::new(&options.roots) {
+            Ok(sandbox) => sandbox,
             Err(err) => {
-                if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                    println!("Stopping server");
+                error!("Failed to resolve allowed roots: {}", err);
+                std::process::exit(1);
+            }
+        },
+        read_only: options.read_only,
+        storage_mount: options.storage_mount,
+        names: NameCache::new()
+    });
+
+    let listener = match TcpListener::bind((options.address.as_str(), options.port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to listen on {}:{}: {}", options.address, options.port, err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(pid_file) = &options.pid_file {
+        if let Err(err) = fs::write(pid_file, std::process::id().to_string()) {
+            warn!("Failed to write process ID to {}: {}", pid_file, err);
+        }
+    }
+
+    let activity = Arc::new(Mutex::new(Activity {
+        connected_clients: 0,
+        last_disconnect: Instant::now()
+    }));
+
+    if let Some(idle_timeout) = options.idle_timeout {
+        let activity = activity.clone();
+        std::thread::spawn(move || stop_when_idle(activity, idle_timeout));
+    }
+
+    let pending_handshakes = Arc::new(AtomicUsize::new(0));
+    for client in listener.incoming() {
+        let mut client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("Failed to accept client: {}", err);
+                continue;
+            }
+        };
+
+        let pending_handshake = match PendingHandshake::new(pending_handshakes.clone()) {
+            Some(pending_handshake) => pending_handshake,
+            None => {
+                warn!("Too many clients are in the handshake, disconnecting a new one");
+                continue;
+            }
+        };
+
+        let activity = activity.clone();
+        let config = config.clone();
+        std::thread::spawn(move || {
+            // A client that never completes the handshake would otherwise hold its thread forever
+            if let Err(err) = client.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+                warn!("Failed to set handshake timeout: {}", err);
+                return;
+            }
+            let hello = handshake(&mut client, &config);
+            drop(pending_handshake);
+            let hello = match hello {
+                Some(hello) => hello,
+                None => return
+            };
+            if let Err(err) = client.set_read_timeout(None) {
+                warn!("Failed to clear handshake timeout: {}", err);
+                return;
+            }
+
+            let writer = match client.try_clone() {
+                Ok(writer) => writer,
+                Err(err) => {
+                    warn!("Failed to clone client stream: {}", err);
                     return;
                 }
-                panic!("{}", err)
+            };
+
+            // Only authenticated clients keep the server running, so that other apps cannot keep a stale server alive
+            let _connection = Connection::new(activity);
+            info!("Client connected");
+            serve(client, writer, config, hello.read_only);
+            info!("Client disconnected");
+        });
+    }
+}
+
+// The token is never passed as an argument, since arguments are visible to every other process
+// A token file is deleted once read, so that it only exists until the server has started
+fn read_token(token_file: &Option<String>) -> Result<String, String> {
+    let token = match token_file {
+        Some(token_file) => {
+            let token = fs::read_to_string(token_file);
+            if let Err(err) = fs::remove_file(token_file) {
+                warn!("Failed to delete token file {}: {}", token_file, err);
+            }
+            match token {
+                Ok(token) => token.trim().to_string(),
+                Err(err) => return Err(format!("Failed to read token from {}: {}", token_file, err))
+            }
+        },
+        None => std::env::var("ANDROIDFS_TOKEN").unwrap_or_default()
+    };
+
+    if token.is_empty() {
+        return Err("--token-file or ANDROIDFS_TOKEN must give the token that clients will authenticate with".to_string());
+    }
+    Ok(token)
+}
+
+// Counts a client as being in the handshake until dropped
+struct PendingHandshake {
+    pending: Arc<AtomicUsize>
+}
+
+impl PendingHandshake {
+    // Returns None if too many clients are already in the handshake
+    fn new(pending: Arc<AtomicUsize>) -> Option<PendingHandshake> {
+        match pending.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| if count < MAX_PENDING_HANDSHAKES { Some(count + 1) } else { None }) {
+            Ok(_) => Some(PendingHandshake {
+                pending
+            }),
+            Err(_) => None
+        }
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Counts a client as connected until dropped, even if handling the client panics
+struct Connection {
+    activity: Arc<Mutex<Activity>>
+}
+
+impl Connection {
+    fn new(activity: Arc<Mutex<Activity>>) -> Connection {
+        activity.lock().unwrap().connected_clients += 1;
+        Connection {
+            activity
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.connected_clients -= 1;
+        activity.last_disconnect = Instant::now();
+    }
+}
+
+// Exits the process once no clients have been connected for idle_timeout
+fn stop_when_idle(activity: Arc<Mutex<Activity>>, idle_timeout: Duration) {
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+
+        let activity = activity.lock().unwrap();
+        if activity.connected_clients == 0 && activity.last_disconnect.elapsed() >= idle_timeout {
+            info!("No clients connected for {} seconds, stopping server", idle_timeout.as_secs());
+            std::process::exit(0);
+        }
+    }
+}
+
+// Handles requests from a client until it disconnects, reading them from reader and writing the responses to writer
+fn serve(mut reader: impl Read, writer: impl Write + Send + 'static, config: Arc<Config>, read_only: bool) {
+    let session = Arc::new(Session {
+        config,
+        read_only,
+        file_handles: Mutex::new(FileHandleMap::new()),
+        directory_handles: Mutex::new(DirectoryHandleMap::new()),
+        watches: Mutex::new(HashMap::new()),
+        writer: Mutex::new(Box::new(writer))
+    });
+
+    let queue = Arc::new(WorkQueue::<u32, (protocol::RequestId, requests::Request)>::new());
+    let mut workers = Vec::new();
+    for _ in 0..WORKER_COUNT {
+        let queue = queue.clone();
+        let session = session.clone();
+        workers.push(std::thread::spawn(move || {
+            while let Some((handle, (request_id, request))) = queue.pop() {
+                // A bug in one handler should fail the request rather than the whole session
+                let result = std::panic::catch_unwind(AssertUnwindSafe(|| handle_request(&session, request_id, request)));
+                if result.is_err() {
+                    write_response::<()>(&session, request_id, Err(responses::ErrorKind::Other.into()));
+                }
+                queue.finish(handle);
+            }
+        }));
+    }
+
+    loop {
+        let (request_id, payload) = match protocol::read_message(&mut reader) {
+            Ok(message) => message,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                    warn!("Failed to read request: {}", err);
+                }
+                break;
             }
         };
 
-        // Read request into a local buffer
-        let mut buf = vec![0u8; length as usize];
-        client.read_exact(&mut buf[..]).unwrap();
-
-        // Deserialize the request
-        let request = bincode::deserialize::<requests::Request>(&buf[..]).unwrap();
-        match request {
-            requests::Request::List(req) => write_response(&mut client, handle_list_files(req)),
-            requests::Request::CreateFile(_) => todo!(),
-            requests::Request::CreateDirectory(_) => todo!(),
-            requests::Request::Open(req) => write_response(&mut client, handle_open(req, &mut file_handles)),
-            requests::Request::Delete(req) => write_response(&mut client, handle_delete_file(req)),
-            requests::Request::Move(req) => write_response(&mut client, handle_move_file(req)),
-            requests::Request::GetFreeSpace => write_response(&mut client, handle_get_free_space()),
-            requests::Request::Stat(req) => write_response(&mut client, handle_stat_file(req)),
-            requests::Request::Read(req) => handle_read_file(req, &mut file_handles, &mut client),
-            requests::Request::Write(req) => handle_write_file(req, &mut file_handles, &mut client),
-            requests::Request::Close(req) => write_response(&mut client, handle_close(req, &mut file_handles)),
-            requests::Request::SetEndOfFile(req) => write_response(&mut client, handle_set_end_of_file(req, &mut file_handles))
+        // The message was framed correctly, so the connection can still be used even if the request itself is invalid
+        let request = match protocol::deserialize::<requests::Request>(&payload[..]) {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("Received invalid request: {}", err);
+                write_response::<()>(&session, request_id, Err(responses::ErrorKind::InvalidRequest.into()));
+                continue;
+            }
         };
+
+        // Requests for the same handle are handled one at a time in the order they were sent, by whichever worker is free first
+        // so that neither they nor any other request wait behind a slow request that they do not depend on
+        queue.push(request_handle(&request), (request_id, request));
+    }
+
+    // Let the workers finish the requests they have already received
+    queue.close();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    for stop in session.watches.lock().unwrap().values() {
+        stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// Returns the file or directory handle that a request operates on, if any
+fn request_handle(request: &requests::Request) -> Option<u32> {
+    match request {
+        requests::Request::Close(handle) => Some(*handle),
+        requests::Request::Read(req) => Some(req.handle),
+        requests::Request::Write(req) => Some(req.handle),
+        requests::Request::SetEndOfFile(req) => Some(req.handle),
+        requests::Request::Flush(req) => Some(req.handle),
+        requests::Request::ReadDirectory(req) => Some(req.handle),
+        requests::Request::CloseDirectory(handle) => Some(*handle),
+        requests::Request::SetTimes(requests::SetTimes { target: requests::FileTarget::Handle(handle), .. }) => Some(*handle),
+        requests::Request::SetMode(requests::SetMode { target: requests::FileTarget::Handle(handle), .. }) => Some(*handle),
+        requests::Request::SetOwner(requests::SetOwner { target: requests::FileTarget::Handle(handle), .. }) => Some(*handle),
+        _ => None
+    }
+}
+
+// Returns true if a request would modify files or affect other clients, so must be rejected in read-only sessions
+fn is_mutating(request: &requests::Request, sandbox: &Sandbox) -> bool {
+    match request {
+        requests::Request::Shutdown => true,
+        requests::Request::Delete(_) => true,
+        requests::Request::CreateFile(_) => true,
+        requests::Request::CreateDirectory(_) => true,
+        requests::Request::Move(_) => true,
+        requests::Request::Write(_) => true,
+        requests::Request::SetEndOfFile(_) => true,
+        requests::Request::SetTimes(_) => true,
+        requests::Request::SetMode(_) => true,
+        requests::Request::SetOwner(_) => true,
+        requests::Request::CreateSymlink(_) => true,
+        requests::Request::Open(req) => match req.disposition {
+            _ if req.access.write || req.access.append => true,
+            requests::Disposition::Open => false,
+            // Only creates the file if it does not exist yet
+            requests::Disposition::OpenOrCreate => sandbox.resolve(&req.path).is_err(),
+            _ => true
+        },
+        _ => false
+    }
+}
+
+fn handle_request(session: &Arc<Session>, request_id: protocol::RequestId, request: requests::Request) {
+    if session.read_only && is_mutating(&request, &session.config.sandbox) {
+        write_response::<()>(session, request_id, Err(responses::ErrorKind::ReadOnly.into()));
+        return;
+    }
+
+    let file_handles = &session.file_handles;
+    let sandbox = &session.config.sandbox;
+    match request {
+        requests::Request::CreateFile(req) => write_response(session, request_id, handle_create_file(req, sandbox)),
+        requests::Request::CreateDirectory(req) => write_response(session, request_id, handle_create_directory(req, sandbox)),
+        requests::Request::Open(req) => write_response(session, request_id, handle_open(req, sandbox, file_handles, session.read_only)),
+        requests::Request::Delete(req) => write_response(session, request_id, handle_delete_file(req, sandbox)),
+        requests::Request::Move(req) => write_response(session, request_id, handle_move_file(req, sandbox)),
+        requests::Request::Read(req) => write_response(session, request_id, handle_read_file(req, file_handles, session, request_id)),
+        requests::Request::Write(req) => write_response(session, request_id, handle_write_file(req, file_handles)),
+        requests::Request::Close(req) => write_response(session, request_id, handle_close(req, file_handles)),
+        requests::Request::SetEndOfFile(req) => write_response(session, request_id, handle_set_end_of_file(req, file_handles)),
+        requests::Request::SetTimes(req) => write_response(session, request_id, handle_set_times(req, sandbox, file_handles)),
+        requests::Request::SetMode(req) => write_response(session, request_id, handle_set_mode(req, sandbox, file_handles)),
+        requests::Request::SetOwner(req) => write_response(session, request_id, handle_set_owner(req, sandbox, file_handles)),
+        requests::Request::CreateSymlink(req) => write_response(session, request_id, handle_create_symlink(req, sandbox)),
+        requests::Request::Flush(req) => write_response(session, request_id, handle_flush(req, file_handles)),
+        requests::Request::SyncFilesystem(req) => write_response(session, request_id, handle_sync_filesystem(req, sandbox)),
+        requests::Request::Watch(req) => {
+            // Replies are sent by the watch itself once it has started
+            if let Err(err) = handle_watch(req, session, request_id) {
+                write_response::<responses::WatchEvent>(session, request_id, Err(err));
+            }
+        },
+        requests::Request::Unwatch(req) => write_response(session, request_id, handle_unwatch(req, &session.watches)),
+        requests::Request::OpenDirectory(req) => write_response(session, request_id, handle_open_directory(req, sandbox, &session.directory_handles)),
+        requests::Request::ReadDirectory(req) => write_response(session, request_id, handle_read_directory(req, sandbox, &session.directory_handles, &session.config.names)),
+        requests::Request::CloseDirectory(req) => write_response(session, request_id, handle_close_directory(req, &session.directory_handles)),
+        requests::Request::Batch(req) => write_response(session, request_id, handle_batch(req, session)),
+        requests::Request::Shutdown => {
+            write_response::<()>(session, request_id, Ok(()));
+            info!("Shutdown requested, stopping server");
+            std::process::exit(0);
+        },
+        request => match handle_metadata_request(session, request) {
+            responses::BatchResponse::List(result) => write_response(session, request_id, result),
+            responses::BatchResponse::Stat(result) => write_response(session, request_id, result),
+            responses::BatchResponse::StatMany(result) => write_response(session, request_id, result),
+            responses::BatchResponse::ListToken(result) => write_response(session, request_id, result),
+            responses::BatchResponse::ReadLink(result) => write_response(session, request_id, result),
+            responses::BatchResponse::GetFreeSpace(result) => write_response(session, request_id, result),
+            responses::BatchResponse::StatFs(result) => write_response(session, request_id, result),
+            responses::BatchResponse::ListVolumes(result) => write_response(session, request_id, result),
+            responses::BatchResponse::Invalid(err) => write_response::<()>(session, request_id, Err(err))
+        }
+    };
+}
+
+// Handles requests that only query metadata, which are the ones that can be batched
+// Any other request is rejected with InvalidRequest
+fn handle_metadata_request(session: &Session, request: requests::Request) -> responses::BatchResponse {
+    let sandbox = &session.config.sandbox;
+    let names = &session.config.names;
+    match request {
+        requests::Request::List(req) => responses::BatchResponse::List(handle_list_files(req, sandbox, names)),
+        requests::Request::Stat(req) => responses::BatchResponse::Stat(handle_stat_file(req, sandbox, names)),
+        requests::Request::StatMany(req) => responses::BatchResponse::StatMany(handle_stat_many(req, sandbox, names)),
+        requests::Request::ListToken(req) => responses::BatchResponse::ListToken(handle_list_token(req, sandbox, names)),
+        requests::Request::ReadLink(req) => responses::BatchResponse::ReadLink(handle_read_link(req, sandbox)),
+        requests::Request::GetFreeSpace => responses::BatchResponse::GetFreeSpace(handle_get_free_space(&session.config.storage_mount)),
+        requests::Request::StatFs(req) => responses::BatchResponse::StatFs(handle_stat_fs(req, sandbox)),
+        requests::Request::ListVolumes => responses::BatchResponse::ListVolumes(handle_list_volumes(sandbox)),
+        _ => responses::BatchResponse::Invalid(responses::ErrorKind::InvalidRequest.into())
     }
 }
 
-fn write_response<T: Serialize>(client: &mut TcpStream, response: responses::Result<T>) {
-    let encoded_response = bincode::serialize(&response).unwrap();
-    client.write_u64::<BigEndian>(encoded_response.len().try_into().unwrap()).unwrap();
-    client.write_all(&encoded_response[..]).unwrap();
+// Each request is handled in turn, and one failing does not stop the rest from being handled
+fn handle_batch(request: requests::Batch, session: &Session) -> responses::Result<responses::Batch> {
+    Ok(request.into_iter().map(|request| handle_metadata_request(session, request)).collect())
+}
+
+// Exchanges hellos with a newly connected client, returning the reply sent if the client uses the same protocol version and presented the correct token
+fn handshake(client: &mut (impl Read + Write), config: &Config) -> Option<responses::Hello> {
+    // The rest of a hello from a different protocol version may not deserialize, in which case we still reply so that the client can report the mismatch
+    let payload = match protocol::read_frame(client) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("Failed to read hello from client: {}", err);
+            return None;
+        }
+    };
+    let hello = match protocol::deserialize::<u32>(&payload[..]) {
+        Ok(protocol_version) if protocol_version != protocol::PROTOCOL_VERSION => Err(protocol_version),
+        _ => Ok(protocol::deserialize::<requests::Hello>(&payload[..]))
+    };
+    let (compatible, authenticated, read_only) = match &hello {
+        Ok(Ok(hello)) => (true, tokens_match(&hello.token, &config.token), hello.read_only || config.read_only),
+        _ => (false, false, true)
+    };
+
+    let reply = responses::Hello {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        build_id: protocol::BUILD_ID.to_string(),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        authenticated: compatible && authenticated,
+        read_only
+    };
+    if let Err(err) = protocol::write_frame(client, &reply) {
+        warn!("Failed to send hello to client: {}", err);
+        return None;
+    }
+
+    match hello {
+        Err(protocol_version) => warn!("Client uses protocol version {}, but this server uses version {}", protocol_version, protocol::PROTOCOL_VERSION),
+        Ok(Err(err)) => warn!("Failed to read hello from client: {}", err),
+        Ok(Ok(_)) if !authenticated => warn!("Client presented an invalid token"),
+        Ok(Ok(_)) => return Some(reply)
+    };
+    None
+}
+
+// Compares the tokens in constant time, so that the token cannot be guessed by timing the comparison
+fn tokens_match(given: &str, expected: &str) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+
+    given.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn write_response<T: Serialize>(session: &Session, request_id: protocol::RequestId, response: responses::Result<T>) {
+    let mut writer = session.writer.lock().unwrap();
+    let result = match protocol::write_message(&mut *writer, request_id, &response) {
+        // Nothing was sent, so the client is told that the request failed instead
+        Err(err) if !matches!(*err, bincode::ErrorKind::Io(_)) => {
+            warn!("Failed to encode response: {}", err);
+            protocol::write_message(&mut *writer, request_id, &encoding_failure(&err))
+        },
+        result => result
+    };
+
+    // If the client has disconnected, the reading thread will notice and stop the session
+    if let Err(err) = result {
+        warn!("Failed to send response: {}", err);
+    }
+}
+
+// Sent in place of a response that could not be encoded
+// e.g. because it had a timestamp from before the epoch, or was too long for the client to accept
+fn encoding_failure(err: &bincode::Error) -> responses::Result<()> {
+    Err(responses::Error {
+        kind: responses::ErrorKind::Other,
+        errno: None,
+        message: format!("Failed to encode response: {}", err)
+    })
 }
 
 
@@ -85,18 +618,22 @@ fn unwrap_or_epoch(result: std::io::Result<SystemTime>) -> SystemTime {
     }
 }
 
-fn handle_close(request: requests::CloseFile, file_handles: &mut FileHandleMap) -> responses::Result<()> {
-    match file_handles.remove(&request) {
+fn get_file(file_handles: &Mutex<FileHandleMap>, handle: FileHandle) -> responses::Result<Arc<fs::File>> {
+    match file_handles.lock().unwrap().get(&handle) {
+        Some(file) => Ok(file.clone()),
+        None => Err(responses::ErrorKind::NoSuchHandle.into())
+    }
+}
+
+fn handle_close(request: requests::CloseFile, file_handles: &Mutex<FileHandleMap>) -> responses::Result<()> {
+    match file_handles.lock().unwrap().remove(&request) {
         Some(_) => Ok(()),
-        None => Err(responses::Error::NoSuchHandle)
+        None => Err(responses::ErrorKind::NoSuchHandle.into())
     }
 }
 
-fn handle_set_end_of_file(request: requests::SetEndOfFile, file_handles: &mut FileHandleMap) -> responses::Result<()> {
-    let file = match file_handles.get(&request.handle) {
-        Some(file) => file,
-        None => return Err(responses::Error::NoSuchHandle)
-    };
+fn handle_set_end_of_file(request: requests::SetEndOfFile, file_handles: &Mutex<FileHandleMap>) -> responses::Result<()> {
+    let file = get_file(file_handles, request.handle)?;
     
     match file.set_len(request.len) {
         Ok(_) => Ok(()),
@@ -104,159 +641,675 @@ fn handle_set_end_of_file(request: requests::SetEndOfFile, file_handles: &mut Fi
     }
 }
 
-fn handle_open(request: requests::OpenFile, file_handles: &mut FileHandleMap) -> responses::Result<FileHandle> {
-    match std::fs::OpenOptions::new()
-        .create(false)
-        .write(true)
-        .read(true)
-        .open(request.path) {
-        Ok(file) => {
-            let mut rng = rand::thread_rng();
-            let mut handle_id: u32;
-            loop {
-                handle_id = rng.gen_range(1..u32::MAX);
-                if !file_handles.contains_key(&handle_id) {
-                    break;
-                }
-            }
+// Converts a timestamp to the representation used by utimensat, where UTIME_OMIT leaves it unchanged
+fn to_timespec(time: Option<SystemTime>) -> libc::timespec {
+    // Times before the epoch cannot be serialized, so never need to be handled
+    let (seconds, nanoseconds) = match time {
+        Some(time) => {
+            let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            (since_epoch.as_secs(), since_epoch.subsec_nanos() as i64)
+        },
+        None => (0, libc::UTIME_OMIT)
+    };
 
-            file_handles.insert(handle_id, file);
-            Ok(handle_id)
+    libc::timespec {
+        tv_sec: seconds as libc::time_t,
+        tv_nsec: nanoseconds as libc::c_long
+    }
+}
+
+fn to_c_path(path: &std::path::Path) -> responses::Result<CString> {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => Ok(path),
+        Err(_) => Err(responses::ErrorKind::InvalidRequest.into())
+    }
+}
+
+// Converts the return value of a libc function into a result, taking the error from errno
+fn check_libc_result(result: libc::c_int) -> responses::Result<()> {
+    if result == 0 {
+        Ok(())
+    }   else    {
+        Err(to_response_error(std::io::Error::last_os_error()))
+    }
+}
+
+fn handle_set_times(request: requests::SetTimes, sandbox: &Sandbox, file_handles: &Mutex<FileHandleMap>) -> responses::Result<()> {
+    let times = [to_timespec(request.accessed), to_timespec(request.modified)];
+    match request.target {
+        requests::FileTarget::Path(path) => {
+            let path = to_c_path(&sandbox.resolve(&path)?)?;
+            check_libc_result(unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) })
         },
+        requests::FileTarget::Handle(handle) => {
+            let file = get_file(file_handles, handle)?;
+            check_libc_result(unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) })
+        }
+    }
+}
+
+fn handle_set_mode(request: requests::SetMode, sandbox: &Sandbox, file_handles: &Mutex<FileHandleMap>) -> responses::Result<()> {
+    let permissions = fs::Permissions::from_mode(request.mode & 0o7777);
+    let result = match request.target {
+        requests::FileTarget::Path(path) => fs::set_permissions(sandbox.resolve(&path)?, permissions),
+        requests::FileTarget::Handle(handle) => get_file(file_handles, handle)?.set_permissions(permissions)
+    };
+
+    match result {
+        Ok(_) => Ok(()),
         Err(err) => Err(to_response_error(err))
     }
 }
 
-fn handle_move_file(request: requests::MoveFile) -> responses::Result<()> {
-    if !request.replace_if_exists && std::path::Path::new(request.to.as_str()).exists() {
-        return Err(responses::Error::FileExists)
+fn handle_set_owner(request: requests::SetOwner, sandbox: &Sandbox, file_handles: &Mutex<FileHandleMap>) -> responses::Result<()> {
+    // chown leaves an ID unchanged if it is -1
+    let uid = request.uid.unwrap_or(u32::MAX) as libc::uid_t;
+    let gid = request.gid.unwrap_or(u32::MAX) as libc::gid_t;
+    match request.target {
+        requests::FileTarget::Path(path) => {
+            let path = to_c_path(&sandbox.resolve(&path)?)?;
+            check_libc_result(unsafe { libc::chown(path.as_ptr(), uid, gid) })
+        },
+        requests::FileTarget::Handle(handle) => {
+            let file = get_file(file_handles, handle)?;
+            check_libc_result(unsafe { libc::fchown(file.as_raw_fd(), uid, gid) })
+        }
     }
+}
 
-    match std::fs::rename(request.from, request.to) {
+fn handle_flush(request: requests::Flush, file_handles: &Mutex<FileHandleMap>) -> responses::Result<()> {
+    let file = get_file(file_handles, request.handle)?;
+
+    match if request.data_only { file.sync_data() } else { file.sync_all() } {
         Ok(_) => Ok(()),
         Err(err) => Err(to_response_error(err))
     }
 }
 
-fn handle_read_file(request: requests::ReadFile, file_handles: &mut FileHandleMap, client: &mut TcpStream) {
-    let file = match file_handles.get_mut(&request.handle) {
-        Some(file) => file,
+fn handle_sync_filesystem(request: requests::SyncFilesystem, sandbox: &Sandbox) -> responses::Result<()> {
+    match request {
+        Some(path) => {
+            let file = match fs::File::open(sandbox.resolve(&path)?) {
+                Ok(file) => file,
+                Err(err) => return Err(to_response_error(err))
+            };
+            check_libc_result(unsafe { libc::syncfs(file.as_raw_fd()) })
+        },
         None => {
-            write_response::<responses::ReadFile>(client, Err(responses::Error::NoSuchHandle));
-            return;
+            unsafe { libc::sync() };
+            Ok(())
         }
-    };
-    let file_length = match file.metadata() {
-        Ok(metadata) => metadata.len(),
-        Err(err) => {
-            write_response::<responses::ReadFile>(client, Err(to_response_error(err)));
-            return;
+    }
+}
+
+// In read-only sessions, files are never created, even if one is deleted after is_mutating checked that it exists
+fn handle_open(mut request: requests::OpenFile, sandbox: &Sandbox, file_handles: &Mutex<FileHandleMap>, read_only: bool) -> responses::Result<FileHandle> {
+    if read_only && request.disposition == requests::Disposition::OpenOrCreate {
+        request.disposition = requests::Disposition::Open;
+    }
+
+    let access = request.access;
+    let read_only_access = !access.write && !access.append;
+    let creates = request.disposition == requests::Disposition::CreateNew
+        || request.disposition == requests::Disposition::OpenOrCreate
+        || request.disposition == requests::Disposition::Supersede;
+    let truncates = request.disposition == requests::Disposition::TruncateExisting
+        || request.disposition == requests::Disposition::Supersede;
+
+    // A file must be opened with some access, and truncating one requires write access
+    if !access.read && read_only_access {
+        return Err(responses::ErrorKind::InvalidRequest.into());
+    }
+    if truncates && !access.write {
+        return Err(responses::ErrorKind::InvalidRequest.into());
+    }
+
+    let mut no_follow = request.no_follow;
+    let path = if no_follow {
+        sandbox.resolve_entry(&request.path)?
+    }   else    {
+        match sandbox.resolve(&request.path) {
+            Ok(path) => path,
+            // The file does not exist yet, so there is no symlink to follow unless it is dangling
+            // Following a dangling symlink could create a file outside of the sandbox, so it is refused instead
+            Err(err) if creates && err.kind == responses::ErrorKind::FileNotFound => {
+                no_follow = true;
+                sandbox.resolve_entry(&request.path)?
+            },
+            Err(err) => return Err(err)
         }
     };
-    match file.seek(std::io::SeekFrom::Start(request.offset)) {
-        Ok(_) => {},
-        Err(err) => {
-            write_response::<responses::ReadFile>(client, Err(to_response_error(err)));
-            return;
+
+    let mut custom_flags = 0;
+    if no_follow {
+        custom_flags |= libc::O_NOFOLLOW;
+    }
+    if request.directory_only {
+        custom_flags |= libc::O_DIRECTORY;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    // open(2) can create a file that is only opened for reading, but OpenOptions refuses to, so the flags are passed directly in that case
+    match request.disposition {
+        requests::Disposition::CreateNew if read_only_access => { custom_flags |= libc::O_CREAT | libc::O_EXCL; },
+        requests::Disposition::OpenOrCreate if read_only_access => { custom_flags |= libc::O_CREAT; },
+        requests::Disposition::CreateNew => { options.create_new(true); },
+        requests::Disposition::OpenOrCreate | requests::Disposition::Supersede => { options.create(true); },
+        requests::Disposition::Open | requests::Disposition::TruncateExisting => {}
+    }
+    options.read(access.read)
+        .write(access.write)
+        .append(access.append)
+        .truncate(truncates)
+        .mode(request.mode)
+        .custom_flags(custom_flags);
+
+    match options.open(path) {
+        Ok(file) => {
+            let mut file_handles = file_handles.lock().unwrap();
+            let handle_id = allocate_handle(&file_handles);
+            file_handles.insert(handle_id, Arc::new(file));
+            Ok(handle_id)
+        },
+        Err(err) => Err(to_response_error(err))
+    }
+}
+
+// Picks a random handle that is not already in use
+fn allocate_handle<T>(handles: &HashMap<u32, T>) -> u32 {
+    let mut rng = rand::thread_rng();
+    loop {
+        let handle_id = rng.gen_range(1..u32::MAX);
+        if !handles.contains_key(&handle_id) {
+            return handle_id;
         }
-    };
+    }
+}
 
+fn create_parents(path: &std::path::Path) -> responses::Result<()> {
+    match path.parent() {
+        Some(parent) => match fs::create_dir_all(parent) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(to_response_error(err))
+        },
+        None => Ok(())
+    }
+}
 
-    let length_readable = std::cmp::min(file_length - request.offset, request.len);
+fn handle_create_file(request: requests::CreateFile, sandbox: &Sandbox) -> responses::Result<()> {
+    // Resolved like an OpenFile, so that an existing symlink to a file within the allowed roots is left as it is
+    let path = match sandbox.resolve(&request.path) {
+        Ok(path) => path,
+        Err(err) if err.kind == responses::ErrorKind::FileNotFound => sandbox.resolve_entry(&request.path)?,
+        Err(err) => return Err(err)
+    };
+    if request.create_parents {
+        create_parents(&path)?;
+    }
 
-    write_response::<responses::ReadFile>(client, Ok(length_readable as u32));
+    // Following a dangling symlink could create a file outside of the sandbox, so it is refused instead
+    let mut options = fs::OpenOptions::new();
+    options.write(true).mode(request.mode).custom_flags(libc::O_NOFOLLOW);
+    if request.exclusive {
+        options.create_new(true);
+    }   else    {
+        options.create(true);
+    }
 
-    let mut limited = file.take(length_readable);
+    match options.open(path) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(to_response_error(err))
+    }
+}
 
-    std::io::copy(&mut limited, client).unwrap();
+fn handle_create_directory(request: requests::CreateDirectory, sandbox: &Sandbox) -> responses::Result<()> {
+    let path = sandbox.resolve_entry(&request.path)?;
+    if request.create_parents {
+        create_parents(&path)?;
+    }
+
+    match fs::DirBuilder::new().mode(request.mode).create(&path) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && !request.exclusive && path.is_dir() => Ok(()),
+        Err(err) => Err(to_response_error(err))
+    }
 }
 
+fn handle_move_file(request: requests::MoveFile, sandbox: &Sandbox) -> responses::Result<()> {
+    let from = sandbox.resolve_entry(&request.from)?;
+    let to = sandbox.resolve_entry(&request.to)?;
+    if !request.replace_if_exists && to.exists() {
+        return Err(responses::ErrorKind::FileExists.into())
+    }
 
-fn handle_write_file(request: requests::WriteFile, file_handles: &mut FileHandleMap, client: &mut TcpStream) {
-    let file = match file_handles.get_mut(&request.handle) {
-        Some(file) => file,
-        None => {
-            write_response::<responses::ReadFile>(client, Err(responses::Error::NoSuchHandle));
-            return;
-        }
+    match std::fs::rename(from, to) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(to_response_error(err))
+    }
+}
+
+// Sends the data read as a series of chunks, then returns the trailer ending the response
+// If reading fails part way through, the error is returned as the trailer
+fn handle_read_file(request: requests::ReadFile, file_handles: &Mutex<FileHandleMap>, session: &Session, request_id: protocol::RequestId) -> responses::Result<responses::ReadChunk> {
+    let file = get_file(file_handles, request.handle)?;
+    match (&*file).seek(std::io::SeekFrom::Start(request.offset)) {
+        Ok(_) => {},
+        Err(err) => return Err(to_response_error(err))
     };
-    match file.seek(std::io::SeekFrom::Start(request.offset)) {
+
+    let mut total_read = 0;
+    while total_read < request.len {
+        let mut chunk = Vec::new();
+        match (&*file).take(std::cmp::min(request.len - total_read, READ_CHUNK_SIZE)).read_to_end(&mut chunk) {
+            Ok(0) => break, // End of file
+            Ok(length) => total_read += length as u64,
+            Err(err) => return Err(to_response_error(err))
+        };
+
+        write_response(session, request_id, Ok(responses::ReadChunk::Data(chunk)));
+    }
+
+    Ok(responses::ReadChunk::End(total_read))
+}
+
+// Replies once the data has been written, with the number of bytes actually written
+// If writing fails part way through, the error is returned even though some of the data may have been written
+fn handle_write_file(request: requests::WriteFile, file_handles: &Mutex<FileHandleMap>) -> responses::Result<responses::WriteFile> {
+    let file = get_file(file_handles, request.handle)?;
+    match (&*file).seek(std::io::SeekFrom::Start(request.offset)) {
         Ok(_) => {},
-        Err(err) => {
-            write_response::<responses::ReadFile>(client, Err(to_response_error(err)));
-            return;
-        }
+        Err(err) => return Err(to_response_error(err))
     };
 
-    write_response::<()>(client, Ok(()));
+    let mut written = 0;
+    while written < request.data.len() {
+        match (&*file).write(&request.data[written..]) {
+            Ok(0) => break,
+            Ok(length) => written += length,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            // Even if some of the data was written, the client must be told that the rest could not be, e.g. because the device is full
+            Err(err) => {
+                warn!("Write to handle {} failed after {} bytes: {}", request.handle, written, err);
+                return Err(to_response_error(err));
+            }
+        }
+    }
 
-    std::io::copy(&mut client.take(request.len), file).unwrap();
+    Ok(written as u64)
 }
 
-fn handle_delete_file(request: requests::DeleteFile) -> responses::Result<()> {
-    let metadata = match fs::metadata(request.clone()) {
+fn handle_delete_file(request: requests::DeleteFile, sandbox: &Sandbox) -> responses::Result<()> {
+    let path = sandbox.resolve_entry(&request)?;
+
+    // Symlinks are deleted rather than their targets
+    let metadata = match fs::symlink_metadata(&path) {
         Ok(metadata) => metadata,
         Err(err) => return Err(to_response_error(err))
     };
 
-    println!("Deleting {}", request);
-    match if metadata.is_dir() { std::fs::remove_dir_all(request) } else { std::fs::remove_file(request) } {
+    debug!("Deleting {}", request);
+    match if metadata.is_dir() { std::fs::remove_dir_all(path) } else { std::fs::remove_file(path) } {
         Ok(_) => Ok(()),
         Err(err) => Err(to_response_error(err))
     }
 }
 
 
-fn handle_get_free_space() -> responses::Result<responses::FreeSpace> {
-    let mut system = sysinfo::System::default();
-    system.refresh_disks_list();
-    system.refresh_disks();
+fn statvfs(path: &std::path::Path) -> responses::Result<libc::statvfs> {
+    let path = to_c_path(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check_libc_result(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })?;
+    Ok(stat)
+}
+
+fn handle_get_free_space(storage_mount: &str) -> responses::Result<responses::FreeSpace> {
+    let stat = statvfs(std::path::Path::new(storage_mount))?;
+    let block_size = stat.f_frsize as u64;
+
+    Ok(responses::FreeSpace {
+        total_bytes: stat.f_blocks as u64 * block_size,
+        free_bytes: stat.f_bavail as u64 * block_size
+    })
+}
+
+fn handle_stat_fs(request: requests::StatFs, sandbox: &Sandbox) -> responses::Result<responses::StatFs> {
+    let path = sandbox.resolve(&request)?;
+    let stat = statvfs(&path)?;
+    let block_size = stat.f_frsize as u64;
+
+    // Only used to report the filesystem type, so failing to read the mount table is not fatal
+    let filesystem_type = match mounts::read_mounts() {
+        Ok(mounts) => mounts::find_mount(&mounts, &path).map(|mount| mount.filesystem_type.clone()),
+        Err(err) => {
+            warn!("Failed to read mount table: {}", err);
+            None
+        }
+    };
+
+    Ok(responses::StatFs {
+        total_bytes: stat.f_blocks as u64 * block_size,
+        free_bytes: stat.f_bfree as u64 * block_size,
+        available_bytes: stat.f_bavail as u64 * block_size,
+        total_inodes: stat.f_files as u64,
+        free_inodes: stat.f_ffree as u64,
+        block_size,
+        filesystem_type,
+        read_only: stat.f_flag & libc::ST_RDONLY == libc::ST_RDONLY
+    })
+}
+
+fn handle_list_volumes(sandbox: &Sandbox) -> responses::Result<responses::ListVolumes> {
+    let mounts = match mounts::read_mounts() {
+        Ok(mounts) => mounts,
+        Err(err) => return Err(to_response_error(err))
+    };
+
+    // A volume may be mounted several times to provide different views of it, in which case the last mount is the one visible
+    let mut volumes: Vec<responses::Volume> = Vec::new();
+    for mount in mounts.iter().rev() {
+        if mount.mount_point.parent() != Some(std::path::Path::new(mounts::STORAGE_DIRECTORY)) {
+            continue;
+        }
+        let name = match mount.mount_point.file_name() {
+            Some(name) if name != mounts::SELF_STORAGE_NAME => name.to_string_lossy().to_string(),
+            _ => continue
+        };
+
+        // Only the current user's directory within the internal storage can be listed
+        let internal = name == mounts::INTERNAL_STORAGE_NAME;
+        let mount_point = if internal {
+            match fs::canonicalize(mounts::SELF_PRIMARY_PATH) {
+                Ok(path) => path,
+                Err(err) => {
+                    warn!("Failed to find the internal storage of the current user: {}", err);
+                    continue;
+                }
+            }
+        }   else    {
+            mount.mount_point.clone()
+        };
+
+        let path = mount_point.to_string_lossy().to_string();
+        if volumes.iter().any(|volume| volume.path == path) || sandbox.resolve(&path).is_err() {
+            continue;
+        }
+
+        let stat = match statvfs(&mount_point) {
+            Ok(stat) => stat,
+            Err(err) => {
+                warn!("Failed to stat volume {}: {}", path, err);
+                continue;
+            }
+        };
+        let block_size = stat.f_frsize as u64;
+
+        volumes.push(responses::Volume {
+            path,
+            label: if internal { "Internal storage" } else { mounts::describe_removable(&mounts, &name) }.to_string(),
+            removable: !internal,
+            filesystem_type: mount.filesystem_type.clone(),
+            total_bytes: stat.f_blocks as u64 * block_size,
+            free_bytes: stat.f_bavail as u64 * block_size
+        });
+    }
+
+    // Internal storage is listed first
+    volumes.reverse();
+    volumes.sort_by_key(|volume| volume.removable);
+    Ok(volumes)
+}
+
+fn handle_watch(request: requests::Watch, session: &Arc<Session>, request_id: protocol::RequestId) -> responses::Result<()> {
+    let root = session.config.sandbox.resolve(&request.path)?;
+    let watcher = match Watcher::new(&root, request.recursive) {
+        Ok(watcher) => watcher,
+        Err(err) => return Err(to_response_error(err))
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    session.watches.lock().unwrap().insert(request_id, stop.clone());
+
+    // Sent before the thread starts so that it always arrives before any changes
+    write_response(session, request_id, Ok(responses::WatchEvent::Started));
+
+    let session = session.clone();
+    std::thread::spawn(move || run_watch(watcher, request.path, stop, &session, request_id));
+    Ok(())
+}
 
-    for disk in system.disks() {
-        if disk.mount_point().to_string_lossy() == "/storage/emulated" {
-            return Ok(responses::FreeSpace {
-                total_bytes: disk.total_space(),
-                free_bytes: disk.available_space()
-            });
+// Sends the changes seen by a watcher to the client until the watch is stopped
+fn run_watch(mut watcher: Watcher, path: String, stop: Arc<AtomicBool>, session: &Session, watch_id: protocol::RequestId) {
+    // Changes are reported within the path the client asked for, which may differ from the resolved one
+    let client_path = |relative: &std::path::Path| if relative.as_os_str().is_empty() {
+        path.clone()
+    }   else    {
+        format!("{}/{}", path.trim_end_matches('/'), relative.to_string_lossy())
+    };
+    let changed = |relative: &std::path::Path, kind: responses::ChangeKind| responses::WatchEvent::Changed(responses::ChangeEvent {
+        path: client_path(relative),
+        kind
+    });
+
+    while !stop.load(Ordering::Relaxed) {
+        let events = match watcher.poll(WATCH_POLL_INTERVAL) {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("Failed to read changes to {}: {}", path, err);
+                break;
+            }
+        };
+
+        for event in events {
+            let event = match event {
+                watcher::Event::Created(relative) => changed(&relative, responses::ChangeKind::Created),
+                watcher::Event::Deleted(relative) => changed(&relative, responses::ChangeKind::Deleted),
+                watcher::Event::Modified(relative) => changed(&relative, responses::ChangeKind::Modified),
+                watcher::Event::Renamed { from, to } => changed(&to, responses::ChangeKind::Renamed { from: client_path(&from) }),
+                watcher::Event::Overflowed => responses::WatchEvent::Overflowed,
+                watcher::Event::Stopped => {
+                    stop.store(true, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            write_response(session, watch_id, Ok(event));
         }
     }
 
-    Err(responses::Error::CouldNotFindDisk)
+    session.watches.lock().unwrap().remove(&watch_id);
+    write_response(session, watch_id, Ok(responses::WatchEvent::Stopped));
 }
 
-fn handle_list_files(request: requests::ListFiles) -> responses::Result<responses::ListFiles> {
-    match fs::read_dir(request) {
+fn handle_unwatch(request: requests::Unwatch, watches: &Mutex<HashMap<protocol::RequestId, Arc<AtomicBool>>>) -> responses::Result<()> {
+    match watches.lock().unwrap().remove(&request) {
+        Some(stop) => {
+            stop.store(true, Ordering::Relaxed);
+            Ok(())
+        },
+        None => Err(responses::ErrorKind::InvalidRequest.into())
+    }
+}
+
+// Tokens are hashes of the files described, so change whenever anything that would be sent changes
+// Each file is hashed separately so that a listing's token can be computed a page at a time by ReadDirectory
+fn hash_file_info(hasher: &mut DefaultHasher, file_info: &FileInfo) {
+    match bincode::serialize(file_info) {
+        Ok(encoded) => hasher.write(&encoded),
+        Err(err) => warn!("Failed to hash {}: {}", file_info.name, err)
+    }
+}
+
+fn conditional<T>(value: T, token: ChangeToken, if_changed: Option<ChangeToken>) -> responses::Conditional<T> {
+    if if_changed == Some(token) {
+        responses::Conditional::NotModified
+    }   else    {
+        responses::Conditional::Modified {
+            value,
+            token
+        }
+    }
+}
+
+fn handle_list_files(request: requests::ListFiles, sandbox: &Sandbox, names: &NameCache) -> responses::Result<responses::ListFiles> {
+    let (files, token) = read_listing(&request.path, request.follow_symlinks, sandbox, names)?;
+    Ok(conditional(files, token, request.if_changed))
+}
+
+// Only the token is sent, so that checking whether a large directory has changed does not mean sending its whole listing
+fn handle_list_token(request: requests::ListToken, sandbox: &Sandbox, names: &NameCache) -> responses::Result<responses::ListToken> {
+    let (_, token) = read_listing(&request.path, request.follow_symlinks, sandbox, names)?;
+    Ok(token)
+}
+
+// Describes every entry of a directory, returning them along with the token of the listing
+fn read_listing(path: &str, follow_symlinks: bool, sandbox: &Sandbox, names: &NameCache) -> responses::Result<(Vec<FileInfo>, ChangeToken)> {
+    let path = sandbox.resolve(path)?;
+    match fs::read_dir(path) {
         Ok(files) => {
-            let files = files.filter_map(|f| { 
+            let files: Vec<FileInfo> = files.filter_map(|f| { 
                 let entry = match f {
                     Ok(file) => file,
                     Err(_) => return None
                 };
 
-                let metadata = match fs::metadata(entry.path()) {
-                    Ok(metadata) => metadata,
-                    Err(_) => return None,
-                };
-
-                Some(metadata_to_file_info(entry.file_name().to_string_lossy().to_string(), metadata))
+                read_file_info(entry.file_name().to_string_lossy().to_string(), &entry.path(), follow_symlinks, sandbox, names).ok()
             }).collect();
 
-            Ok(files)
+            let mut hasher = DefaultHasher::new();
+            for file_info in &files {
+                hash_file_info(&mut hasher, file_info);
+            }
+            Ok((files, hasher.finish()))
         },
         Err(err) => Err(to_response_error(err))
     }
 }
 
-fn handle_stat_file(request: requests::StatFile) -> responses::Result<responses::StatFile> {
-    match fs::metadata(&request) {
-        Ok(metadata) => Ok(metadata_to_file_info(request, metadata)),
-        Err(err) => {
-            Err(to_response_error(err))
+fn handle_open_directory(request: requests::OpenDirectory, sandbox: &Sandbox, directory_handles: &Mutex<DirectoryHandleMap>) -> responses::Result<responses::OpenDirectory> {
+    let path = sandbox.resolve(&request.path)?;
+    match fs::read_dir(path) {
+        Ok(entries) => {
+            let mut directory_handles = directory_handles.lock().unwrap();
+            let handle_id = allocate_handle(&directory_handles);
+            directory_handles.insert(handle_id, Arc::new(Mutex::new(OpenDirectory {
+                entries,
+                follow_symlinks: request.follow_symlinks,
+                hasher: DefaultHasher::new()
+            })));
+            Ok(handle_id)
+        },
+        Err(err) => Err(to_response_error(err))
+    }
+}
+
+fn handle_read_directory(request: requests::ReadDirectory, sandbox: &Sandbox, directory_handles: &Mutex<DirectoryHandleMap>, names: &NameCache) -> responses::Result<responses::ReadDirectory> {
+    if request.max_entries == 0 {
+        return Err(responses::ErrorKind::InvalidRequest.into());
+    }
+
+    let directory = match directory_handles.lock().unwrap().get(&request.handle) {
+        Some(directory) => directory.clone(),
+        None => return Err(responses::ErrorKind::NoSuchHandle.into())
+    };
+    let mut directory = directory.lock().unwrap();
+
+    // Entries that cannot be described, e.g. because they were deleted after being read, are skipped as in ListFiles
+    let max_entries = request.max_entries.min(MAX_DIRECTORY_ENTRIES) as usize;
+    let mut entries = Vec::new();
+    while entries.len() < max_entries {
+        let entry = match directory.entries.next() {
+            Some(Ok(entry)) => entry,
+            Some(Err(_)) => continue,
+            None => return Ok(responses::ReadDirectory {
+                entries,
+                end: true,
+                token: Some(directory.hasher.finish())
+            })
+        };
+
+        if let Ok(file_info) = read_file_info(entry.file_name().to_string_lossy().to_string(), &entry.path(), directory.follow_symlinks, sandbox, names) {
+            hash_file_info(&mut directory.hasher, &file_info);
+            entries.push(file_info);
         }
     }
+
+    Ok(responses::ReadDirectory {
+        entries,
+        end: false,
+        token: None
+    })
+}
+
+fn handle_close_directory(request: requests::CloseDirectory, directory_handles: &Mutex<DirectoryHandleMap>) -> responses::Result<()> {
+    match directory_handles.lock().unwrap().remove(&request) {
+        Some(_) => Ok(()),
+        None => Err(responses::ErrorKind::NoSuchHandle.into())
+    }
+}
+
+fn handle_stat_file(request: requests::StatFile, sandbox: &Sandbox, names: &NameCache) -> responses::Result<responses::StatFile> {
+    let path = sandbox.resolve_entry(&request.path)?;
+    let file_info = read_file_info(request.path, &path, request.follow_symlinks, sandbox, names)?;
+
+    let mut hasher = DefaultHasher::new();
+    hash_file_info(&mut hasher, &file_info);
+    Ok(conditional(file_info, hasher.finish(), request.if_changed))
 }
 
-fn metadata_to_file_info(file_name: String, metadata: fs::Metadata) -> FileInfo {
+fn handle_stat_many(request: requests::StatMany, sandbox: &Sandbox, names: &NameCache) -> responses::Result<responses::StatMany> {
+    let follow_symlinks = request.follow_symlinks;
+    Ok(request.paths.into_iter().map(|path| {
+        let resolved = sandbox.resolve_entry(&path)?;
+        read_file_info(path, &resolved, follow_symlinks, sandbox, names)
+    }).collect())
+}
+
+fn handle_read_link(request: requests::ReadLink, sandbox: &Sandbox) -> responses::Result<responses::ReadLink> {
+    let path = sandbox.resolve_entry(&request)?;
+    match fs::read_link(path) {
+        Ok(target) => Ok(target.to_string_lossy().to_string()),
+        Err(err) => Err(to_response_error(err))
+    }
+}
+
+fn handle_create_symlink(request: requests::CreateSymlink, sandbox: &Sandbox) -> responses::Result<()> {
+    // The target is not checked, as following the link later is still restricted to the allowed roots
+    let path = sandbox.resolve_entry(&request.path)?;
+    match std::os::unix::fs::symlink(request.target, path) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(to_response_error(err))
+    }
+}
+
+// Describes the entry at path without following it if it is a symlink, unless follow_symlinks is true
+// Links that are broken or point outside of the allowed roots are described by the link itself, so that they are not hidden from listings
+fn read_file_info(file_name: String, path: &std::path::Path, follow_symlinks: bool, sandbox: &Sandbox, names: &NameCache) -> responses::Result<FileInfo> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => return Err(to_response_error(err))
+    };
+    if !metadata.file_type().is_symlink() {
+        return Ok(metadata_to_file_info(file_name, metadata, None, names));
+    }
+
+    let target = match fs::read_link(path) {
+        Ok(target) => target,
+        Err(err) => return Err(to_response_error(err))
+    };
+    let target_metadata = sandbox.resolve(&path.to_string_lossy())
+        .ok()
+        .and_then(|resolved| fs::metadata(resolved).ok());
+
+    let link = LinkInfo {
+        target: target.to_string_lossy().to_string(),
+        target_mode: target_metadata.as_ref().map(|target_metadata| target_metadata.mode())
+    };
+    match target_metadata {
+        Some(target_metadata) if follow_symlinks => Ok(metadata_to_file_info(file_name, target_metadata, Some(link), names)),
+        _ => Ok(metadata_to_file_info(file_name, metadata, Some(link), names))
+    }
+}
+
+fn metadata_to_file_info(file_name: String, metadata: fs::Metadata, link: Option<LinkInfo>, names: &NameCache) -> FileInfo {
     FileInfo 
     {
         name: file_name,
@@ -265,19 +1318,539 @@ fn metadata_to_file_info(file_name: String, metadata: fs::Metadata) -> FileInfo
         last_modified: unwrap_or_epoch(metadata.modified()),
         creation_time: unwrap_or_epoch(metadata.created()),
         mode: metadata.mode(),
-        ino: metadata.ino()
+        ino: metadata.ino(),
+        link,
+        nlink: metadata.nlink(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        dev: metadata.dev(),
+        blocks: metadata.blocks(),
+        owner: names.user_name(metadata.uid()),
+        group: names.group_name(metadata.gid())
     }
 }
 
 fn to_response_error(err: std::io::Error) -> responses::Error {
-    match err.raw_os_error() {
-        Some(os_err) => {
-            match os_err {
-                2 => responses::Error::FileNotFound,
-                13 => responses::Error::PermissionDenied ,
-                _ => responses::Error::Other
-            }
+    let kind = match err.raw_os_error() {
+        Some(errno) => match errno {
+            libc::ENOENT => responses::ErrorKind::FileNotFound,
+            libc::EACCES | libc::EPERM => responses::ErrorKind::PermissionDenied,
+            libc::EEXIST => responses::ErrorKind::FileExists,
+            libc::ENOSPC | libc::EDQUOT => responses::ErrorKind::NoSpace,
+            libc::ENOTDIR => responses::ErrorKind::NotADirectory,
+            libc::EISDIR => responses::ErrorKind::IsADirectory,
+            libc::ENOTEMPTY => responses::ErrorKind::DirectoryNotEmpty,
+            libc::EROFS => responses::ErrorKind::ReadOnlyFilesystem,
+            libc::ENAMETOOLONG => responses::ErrorKind::NameTooLong,
+            libc::EXDEV => responses::ErrorKind::CrossDevice,
+            libc::EBUSY | libc::ETXTBSY => responses::ErrorKind::Busy,
+            libc::EMFILE | libc::ENFILE => responses::ErrorKind::TooManyOpenFiles,
+            libc::EINTR => responses::ErrorKind::Interrupted,
+            _ => responses::ErrorKind::Other
         },
-        _ => responses::Error::Other,
+        None => responses::ErrorKind::Other
+    };
+
+    responses::Error {
+        kind,
+        errno: err.raw_os_error(),
+        message: err.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::thread::JoinHandle;
+    use serde::de::DeserializeOwned;
+
+    // A client connected to a session running on another thread
+    struct TestClient {
+        stream: UnixStream,
+        session: Option<JoinHandle<()>>,
+        root: PathBuf
     }
-}
\ No newline at end of file
+
+    impl TestClient {
+        // Starts a read-write session whose only allowed root is a new empty directory
+        fn new(name: &str) -> TestClient {
+            TestClient::start(name, false)
+        }
+
+        fn read_only(name: &str) -> TestClient {
+            TestClient::start(name, true)
+        }
+
+        fn start(name: &str, read_only: bool) -> TestClient {
+            let root = std::env::temp_dir().join(format!("androidfs_server_test_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            let root = fs::canonicalize(root).unwrap();
+            let config = test_config(&root);
+
+            let (stream, server) = UnixStream::pair().unwrap();
+            let writer = server.try_clone().unwrap();
+            TestClient {
+                stream,
+                session: Some(std::thread::spawn(move || serve(server, writer, config, read_only))),
+                root
+            }
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.root.join(name).to_str().unwrap().to_string()
+        }
+
+        fn send(&mut self, request_id: protocol::RequestId, request: &requests::Request) {
+            protocol::write_message(&mut self.stream, request_id, request).unwrap();
+        }
+
+        fn send_payload(&mut self, request_id: protocol::RequestId, payload: &[u8]) {
+            protocol::write_encoded_message(&mut self.stream, request_id, payload).unwrap();
+        }
+
+        fn receive<T: DeserializeOwned>(&mut self, request_id: protocol::RequestId) -> responses::Result<T> {
+            let (received_id, payload) = protocol::read_message(&mut self.stream).unwrap();
+            assert_eq!(received_id, request_id);
+            protocol::deserialize(&payload[..]).unwrap()
+        }
+
+        fn open_or_create_for_reading(&self, path: String) -> requests::Request {
+            requests::Request::Open(requests::OpenFile {
+                path,
+                access: requests::AccessMode {
+                    read: true,
+                    write: false,
+                    append: false
+                },
+                disposition: requests::Disposition::OpenOrCreate,
+                mode: 0o644,
+                no_follow: false,
+                directory_only: false
+            })
+        }
+
+        fn stat(&self, path: String) -> requests::Request {
+            requests::Request::Stat(requests::StatFile {
+                path,
+                follow_symlinks: true,
+                if_changed: None
+            })
+        }
+
+        // Checks that the session still handles valid requests
+        fn assert_alive(&mut self) {
+            let request = self.stat(self.path(""));
+            self.send(1000, &request);
+            assert!(self.receive::<responses::StatFile>(1000).is_ok());
+        }
+
+        // Waits for the session to end, which it should once the connection is closed
+        fn finish(mut self) {
+            self.stream.shutdown(std::net::Shutdown::Both).unwrap();
+            self.session.take().unwrap().join().unwrap();
+            fs::remove_dir_all(&self.root).unwrap();
+        }
+    }
+
+    fn test_config(root: &std::path::Path) -> Arc<Config> {
+        Arc::new(Config {
+            token: String::new(),
+            sandbox: Sandbox::new(&[root.to_str().unwrap().to_string()]).unwrap(),
+            read_only: false,
+            storage_mount: root.to_str().unwrap().to_string(),
+            names: NameCache::new()
+        })
+    }
+
+    fn error_kind<T>(response: responses::Result<T>) -> Option<responses::ErrorKind> {
+        response.err().map(|err| err.kind)
+    }
+
+    #[test]
+    fn jobs_with_the_same_key_are_taken_in_order_one_at_a_time() {
+        let queue = WorkQueue::<u32, &str>::new();
+        queue.push(None, "slow listing");
+        queue.push(Some(1), "first read");
+        queue.push(Some(1), "second read");
+        queue.push(Some(2), "other read");
+
+        // A worker busy with a job without a key does not hold up jobs with one
+        assert_eq!(queue.pop(), Some((None, "slow listing")));
+        assert_eq!(queue.pop(), Some((Some(1), "first read")));
+        // The second read waits for the first, but does not hold up the other handle
+        assert_eq!(queue.pop(), Some((Some(2), "other read")));
+        queue.finish(Some(1));
+        assert_eq!(queue.pop(), Some((Some(1), "second read")));
+
+        queue.close();
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn hellos_longer_than_a_frame_are_refused() {
+        let config = test_config(&std::env::temp_dir());
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(&protocol::MAX_MESSAGE_LENGTH.to_be_bytes()).unwrap();
+        assert!(handshake(&mut server, &config).is_none());
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        protocol::write_frame(&mut client, &requests::Hello {
+            protocol_version: protocol::PROTOCOL_VERSION,
+            token: String::new(),
+            read_only: false
+        }).unwrap();
+        assert!(handshake(&mut server, &config).is_some());
+    }
+
+    #[test]
+    fn handshakes_in_progress_are_limited() {
+        let pending = Arc::new(AtomicUsize::new(0));
+        let mut handshakes: Vec<PendingHandshake> = (0..MAX_PENDING_HANDSHAKES).map(|_| PendingHandshake::new(pending.clone()).unwrap()).collect();
+        assert!(PendingHandshake::new(pending.clone()).is_none());
+
+        handshakes.pop();
+        assert!(PendingHandshake::new(pending.clone()).is_some());
+    }
+
+    #[test]
+    fn random_payloads_are_invalid_requests() {
+        let mut client = TestClient::new("random_payloads");
+
+        let mut payload = [0u8; 64];
+        rand::thread_rng().fill(&mut payload[..]);
+        // Makes sure that the payload does not start with the index of a real request
+        payload[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        client.send_payload(1, &payload[..]);
+        assert_eq!(error_kind(client.receive::<()>(1)), Some(responses::ErrorKind::InvalidRequest));
+
+        client.send_payload(2, &[]);
+        assert_eq!(error_kind(client.receive::<()>(2)), Some(responses::ErrorKind::InvalidRequest));
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn huge_lengths_are_invalid_requests() {
+        let mut client = TestClient::new("huge_lengths");
+
+        // A path whose length prefix claims far more data than the message contains
+        let mut payload = bincode::serialize(&client.stat(String::new())).unwrap();
+        payload[4..12].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        client.send_payload(1, &payload[..]);
+        assert_eq!(error_kind(client.receive::<()>(1)), Some(responses::ErrorKind::InvalidRequest));
+
+        let mut payload = bincode::serialize(&requests::Request::Batch(Vec::new())).unwrap();
+        payload[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        client.send_payload(2, &payload[..]);
+        assert_eq!(error_kind(client.receive::<()>(2)), Some(responses::ErrorKind::InvalidRequest));
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn nested_batches_are_rejected() {
+        let mut client = TestClient::new("nested_batches");
+
+        let request = requests::Request::Batch(vec![
+            requests::Request::Batch(vec![client.stat(client.path(""))]),
+            client.stat(client.path(""))
+        ]);
+        client.send(1, &request);
+        let responses = client.receive::<responses::Batch>(1).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert!(matches!(&responses[0], responses::BatchResponse::Invalid(err) if err.kind == responses::ErrorKind::InvalidRequest));
+        assert!(matches!(&responses[1], responses::BatchResponse::Stat(Ok(_))));
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn unknown_handles_are_rejected() {
+        let mut client = TestClient::new("unknown_handles");
+
+        client.send(1, &requests::Request::Read(requests::ReadFile {
+            handle: 12345,
+            offset: 0,
+            len: 16
+        }));
+        assert_eq!(error_kind(client.receive::<responses::ReadChunk>(1)), Some(responses::ErrorKind::NoSuchHandle));
+
+        client.send(2, &requests::Request::Close(12345));
+        assert_eq!(error_kind(client.receive::<()>(2)), Some(responses::ErrorKind::NoSuchHandle));
+
+        client.send(3, &requests::Request::CloseDirectory(12345));
+        assert_eq!(error_kind(client.receive::<()>(3)), Some(responses::ErrorKind::NoSuchHandle));
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn oversized_frames_close_the_connection() {
+        let mut client = TestClient::new("oversized_frames");
+
+        let request = client.stat(client.path(""));
+        client.send(1, &request);
+        client.stream.write_all(&(protocol::MAX_MESSAGE_LENGTH + 1).to_be_bytes()).unwrap();
+        client.stream.write_all(&2u32.to_be_bytes()).unwrap();
+
+        // Requests before the bad frame are still answered, but nothing after it can be trusted
+        assert!(client.receive::<responses::StatFile>(1).is_ok());
+        assert!(protocol::read_message(&mut client.stream).is_err());
+
+        client.finish();
+    }
+
+    // Creates an empty directory outside of the client's root, for the client to try to escape to
+    fn outside_directory(name: &str) -> PathBuf {
+        let outside = std::env::temp_dir().join(format!("androidfs_server_test_{}_{}_outside", std::process::id(), name));
+        let _ = fs::remove_dir_all(&outside);
+        fs::create_dir_all(&outside).unwrap();
+        fs::canonicalize(outside).unwrap()
+    }
+
+    fn create_file(path: String, create_parents: bool) -> requests::Request {
+        requests::Request::CreateFile(requests::CreateFile {
+            path,
+            mode: 0o644,
+            exclusive: false,
+            create_parents
+        })
+    }
+
+    #[test]
+    fn paths_cannot_escape_the_root_with_parent_components() {
+        let mut client = TestClient::new("parent_components");
+        let outside = outside_directory("parent_components");
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        let escape = format!("{}/../{}", client.path(""), outside.file_name().unwrap().to_str().unwrap());
+
+        let request = client.stat(format!("{}/secret", escape));
+        client.send(1, &request);
+        assert_eq!(error_kind(client.receive::<responses::StatFile>(1)), Some(responses::ErrorKind::PermissionDenied));
+
+        client.send(2, &create_file(format!("{}/new", escape), false));
+        assert_eq!(error_kind(client.receive::<()>(2)), Some(responses::ErrorKind::PermissionDenied));
+        assert!(!outside.join("new").exists());
+
+        // Parents that do not exist yet cannot be resolved, so a .. among them is refused outright
+        client.send(3, &create_file(client.path("missing/../../escaped"), true));
+        assert!(client.receive::<()>(3).is_err());
+        assert!(!client.root.join("missing").exists());
+        assert!(!client.root.parent().unwrap().join("escaped").exists());
+
+        client.send(4, &requests::Request::Delete(format!("{}/secret", escape)));
+        assert_eq!(error_kind(client.receive::<()>(4)), Some(responses::ErrorKind::PermissionDenied));
+        assert!(outside.join("secret").exists());
+
+        client.assert_alive();
+        client.finish();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn paths_cannot_escape_the_root_through_symlinked_directories() {
+        let mut client = TestClient::new("symlinked_directories");
+        let outside = outside_directory("symlinked_directories");
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(&outside, client.root.join("link")).unwrap();
+
+        let request = client.stat(client.path("link/secret"));
+        client.send(1, &request);
+        assert_eq!(error_kind(client.receive::<responses::StatFile>(1)), Some(responses::ErrorKind::PermissionDenied));
+
+        client.send(2, &create_file(client.path("link/new"), false));
+        assert_eq!(error_kind(client.receive::<()>(2)), Some(responses::ErrorKind::PermissionDenied));
+        assert!(!outside.join("new").exists());
+
+        client.send(3, &create_file(client.path("link/missing/new"), true));
+        assert_eq!(error_kind(client.receive::<()>(3)), Some(responses::ErrorKind::PermissionDenied));
+        assert!(!outside.join("missing").exists());
+
+        client.send(4, &requests::Request::Delete(client.path("link/secret")));
+        assert_eq!(error_kind(client.receive::<()>(4)), Some(responses::ErrorKind::PermissionDenied));
+        assert!(outside.join("secret").exists());
+
+        // The link itself is within the root, so deleting it leaves the directory it points to alone
+        client.send(5, &requests::Request::Delete(client.path("link")));
+        assert!(client.receive::<()>(5).is_ok());
+        assert!(outside.join("secret").exists());
+
+        client.assert_alive();
+        client.finish();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn missing_parents_are_created_within_the_root() {
+        let mut client = TestClient::new("missing_parents");
+
+        client.send(1, &create_file(client.path("a/b/c"), true));
+        assert!(client.receive::<()>(1).is_ok());
+        assert!(client.root.join("a/b/c").is_file());
+
+        client.send(2, &create_file(client.path("d/e"), false));
+        assert_eq!(error_kind(client.receive::<()>(2)), Some(responses::ErrorKind::FileNotFound));
+        assert!(!client.root.join("d").exists());
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn files_are_not_created_through_dangling_symlinks() {
+        let mut client = TestClient::new("dangling_symlinks");
+        let outside = std::env::temp_dir().join(format!("androidfs_server_test_{}_outside", std::process::id()));
+        let _ = fs::remove_file(&outside);
+        std::os::unix::fs::symlink(&outside, client.root.join("link")).unwrap();
+
+        for (request_id, exclusive) in [(1, false), (2, true)] {
+            client.send(request_id, &requests::Request::CreateFile(requests::CreateFile {
+                path: client.path("link"),
+                mode: 0o644,
+                exclusive,
+                create_parents: false
+            }));
+            assert!(client.receive::<()>(request_id).is_err());
+            assert!(!outside.exists());
+        }
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn list_tokens_match_paged_listings() {
+        let mut client = TestClient::new("list_tokens");
+        for name in ["a", "b", "c"] {
+            fs::write(client.root.join(name), name).unwrap();
+        }
+
+        client.send(1, &requests::Request::ListToken(requests::ListToken {
+            path: client.path(""),
+            follow_symlinks: true
+        }));
+        let token = client.receive::<responses::ListToken>(1).unwrap();
+
+        client.send(2, &requests::Request::OpenDirectory(requests::OpenDirectory {
+            path: client.path(""),
+            follow_symlinks: true
+        }));
+        let handle = client.receive::<responses::OpenDirectory>(2).unwrap();
+        client.send(3, &requests::Request::ReadDirectory(requests::ReadDirectory {
+            handle,
+            max_entries: 16
+        }));
+        let page = client.receive::<responses::ReadDirectory>(3).unwrap();
+        assert!(page.end);
+        assert_eq!(page.token, Some(token));
+
+        client.finish();
+    }
+
+    #[test]
+    fn oversized_responses_become_errors() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let session = Session {
+            config: test_config(&std::env::temp_dir()),
+            read_only: false,
+            file_handles: Mutex::new(FileHandleMap::new()),
+            directory_handles: Mutex::new(DirectoryHandleMap::new()),
+            watches: Mutex::new(HashMap::new()),
+            writer: Mutex::new(Box::new(server))
+        };
+
+        // No request has a response this large, so one is written directly
+        write_response::<Vec<u8>>(&session, 1, Ok(vec![0; protocol::MAX_MESSAGE_LENGTH as usize]));
+        let (request_id, payload) = protocol::read_message(&mut client).unwrap();
+        assert_eq!(request_id, 1);
+        assert_eq!(error_kind(protocol::deserialize::<responses::Result<Vec<u8>>>(&payload[..]).unwrap()), Some(responses::ErrorKind::Other));
+    }
+
+    #[test]
+    fn reads_past_the_end_of_a_file_return_no_data() {
+        let mut client = TestClient::new("reads_past_end");
+        fs::write(client.root.join("file"), b"hello").unwrap();
+
+        client.send(1, &requests::Request::Open(requests::OpenFile {
+            path: client.path("file"),
+            access: requests::AccessMode {
+                read: true,
+                write: false,
+                append: false
+            },
+            disposition: requests::Disposition::Open,
+            mode: 0,
+            no_follow: false,
+            directory_only: false
+        }));
+        let handle = client.receive::<FileHandle>(1).unwrap();
+
+        client.send(2, &requests::Request::Read(requests::ReadFile {
+            handle,
+            offset: 100,
+            len: 16
+        }));
+        assert!(matches!(client.receive::<responses::ReadChunk>(2), Ok(responses::ReadChunk::End(0))));
+
+        client.send(3, &requests::Request::Read(requests::ReadFile {
+            handle,
+            offset: 3,
+            len: 16
+        }));
+        assert!(matches!(client.receive::<responses::ReadChunk>(3), Ok(responses::ReadChunk::Data(data)) if data == b"lo"));
+        assert!(matches!(client.receive::<responses::ReadChunk>(3), Ok(responses::ReadChunk::End(2))));
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn block_devices_are_parsed_from_vold_sources() {
+        assert_eq!(mounts::parse_block_device("/dev/block/vold/public:179,129"), Some((179, 129)));
+        assert_eq!(mounts::parse_block_device("/dev/block/vold/public:8,1"), Some((8, 1)));
+        assert_eq!(mounts::parse_block_device("/mnt/media_rw/1234-ABCD"), None);
+        assert_eq!(mounts::parse_block_device("tmpfs"), None);
+    }
+
+    #[test]
+    fn files_can_be_created_for_reading_only() {
+        let mut client = TestClient::new("create_for_reading");
+
+        let request = client.open_or_create_for_reading(client.path("file"));
+        client.send(1, &request);
+        assert!(client.receive::<FileHandle>(1).is_ok());
+        assert!(client.root.join("file").is_file());
+
+        client.send(2, &request);
+        assert!(client.receive::<FileHandle>(2).is_ok());
+
+        client.assert_alive();
+        client.finish();
+    }
+
+    #[test]
+    fn read_only_sessions_open_existing_files_but_do_not_create_them() {
+        let mut client = TestClient::read_only("read_only_open_or_create");
+        fs::write(client.root.join("existing"), b"hello").unwrap();
+
+        let request = client.open_or_create_for_reading(client.path("existing"));
+        client.send(1, &request);
+        assert!(client.receive::<FileHandle>(1).is_ok());
+
+        let request = client.open_or_create_for_reading(client.path("missing"));
+        client.send(2, &request);
+        assert_eq!(error_kind(client.receive::<FileHandle>(2)), Some(responses::ErrorKind::ReadOnly));
+        assert!(!client.root.join("missing").exists());
+
+        client.assert_alive();
+        client.finish();
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::log::*;
+
+// Events that are reported for every watched directory
+const WATCH_MASK: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MODIFY | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+
+// Large enough to hold many events with names of the maximum length
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Size of struct inotify_event without the name that follows it
+const EVENT_HEADER_SIZE: usize = 16;
+
+// Paths are relative to the watched path, and empty for the watched path itself
+pub enum Event {
+    Created(PathBuf),
+    Deleted(PathBuf),
+    Modified(PathBuf),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf
+    },
+    // The kernel's queue of events filled up, so some changes were lost
+    Overflowed,
+    // The watched path was deleted or moved away, so no more events will be reported
+    Stopped
+}
+
+// Watches a file, or a directory and optionally everything within it, using inotify
+pub struct Watcher {
+    fd: libc::c_int,
+    recursive: bool,
+    // Watched directories, relative to root, keyed by their watch descriptors
+    directories: HashMap<libc::c_int, PathBuf>,
+    root: PathBuf,
+    root_descriptor: libc::c_int,
+    // Set once inotify_add_watch fails with ENOSPC, after which no more directories are watched
+    watch_limit_reached: bool
+}
+
+impl Watcher {
+    // root must already be resolved, and subdirectories are found without following symlinks
+    pub fn new(root: &Path, recursive: bool) -> io::Result<Watcher> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut watcher = Watcher {
+            fd,
+            recursive,
+            directories: HashMap::new(),
+            root: root.to_path_buf(),
+            root_descriptor: -1,
+            watch_limit_reached: false
+        };
+        watcher.root_descriptor = watcher.add_watch(PathBuf::new())?;
+        if recursive {
+            let mut unwatched = 0;
+            watcher.add_subdirectories(Path::new(""), &mut unwatched);
+            watcher.report_unwatched(false, unwatched);
+        }
+
+        Ok(watcher)
+    }
+
+    fn add_watch(&mut self, relative: PathBuf) -> io::Result<libc::c_int> {
+        let path = match CString::new(self.root.join(&relative).as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+
+        let descriptor = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK | libc::IN_DONT_FOLLOW) };
+        if descriptor < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.directories.insert(descriptor, relative);
+        Ok(descriptor)
+    }
+
+    // Watches relative and every directory within it, skipping any that cannot be watched
+    // Directories found once the limit on the number of watches has been reached are added to unwatched without being descended into
+    fn add_directory(&mut self, relative: PathBuf, unwatched: &mut usize) {
+        if self.watch_limit_reached {
+            *unwatched += 1;
+            return;
+        }
+
+        match self.add_watch(relative.clone()) {
+            Ok(_) => self.add_subdirectories(&relative, unwatched),
+            Err(err) if err.raw_os_error() == Some(libc::ENOSPC) => {
+                self.watch_limit_reached = true;
+                *unwatched += 1;
+            },
+            Err(err) => debug!("Failed to watch {}: {}", self.root.join(&relative).display(), err)
+        }
+    }
+
+    fn add_subdirectories(&mut self, relative: &Path, unwatched: &mut usize) {
+        let entries = match fs::read_dir(self.root.join(relative)) {
+            Ok(entries) => entries,
+            Err(_) => return
+        };
+
+        for entry in entries.flatten() {
+            let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+            if is_dir {
+                self.add_directory(relative.join(entry.file_name()), unwatched);
+            }
+        }
+    }
+
+    // Warns only when the limit is first reached, as every directory found afterwards is left unwatched too
+    fn report_unwatched(&self, limit_was_reached: bool, unwatched: usize) {
+        if self.watch_limit_reached && !limit_was_reached {
+            warn!("Reached the limit on the number of inotify watches (fs.inotify.max_user_watches), so {} directories within {} and everything within them are not watched",
+                unwatched, self.root.display());
+        }   else if unwatched > 0 {
+            debug!("Left {} more directories within {} unwatched", unwatched, self.root.display());
+        }
+    }
+
+    // Waits up to timeout for changes, returning any that occurred
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Vec<Event>> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) } < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::Interrupted { Ok(Vec::new()) } else { Err(err) };
+        }
+
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let length = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if length < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted { Ok(Vec::new()) } else { Err(err) };
+        }
+
+        Ok(self.parse_events(&buffer[..length as usize]))
+    }
+
+    fn parse_events(&mut self, mut buffer: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        // Renames are reported as a pair of events sharing a cookie, which are combined into one
+        let mut moved_from: HashMap<u32, PathBuf> = HashMap::new();
+
+        while buffer.len() >= EVENT_HEADER_SIZE {
+            let read_u32 = |offset: usize| u32::from_ne_bytes([buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]]);
+            let descriptor = read_u32(0) as libc::c_int;
+            let mask = read_u32(4);
+            let cookie = read_u32(8);
+            let name_length = read_u32(12) as usize;
+
+            let name_bytes = &buffer[EVENT_HEADER_SIZE..EVENT_HEADER_SIZE + name_length];
+            let name = &name_bytes[..name_bytes.iter().position(|byte| *byte == 0).unwrap_or(name_length)];
+            buffer = &buffer[EVENT_HEADER_SIZE + name_length..];
+
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                events.push(Event::Overflowed);
+                continue;
+            }
+
+            let directory = match self.directories.get(&descriptor) {
+                Some(directory) => directory.clone(),
+                None => continue
+            };
+            let path = directory.join(std::ffi::OsStr::from_bytes(name));
+
+            if mask & libc::IN_IGNORED != 0 {
+                self.directories.remove(&descriptor);
+                continue;
+            }
+
+            // Events about a watched directory itself are only of interest for the root, the rest are also reported by their parent
+            if mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                if descriptor == self.root_descriptor {
+                    events.push(Event::Deleted(PathBuf::new()));
+                    events.push(Event::Stopped);
+                }
+                continue;
+            }
+
+            if self.recursive && mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                // Anything created within the directory before it was watched is not reported
+                // Directories moved within the watched ones are already watched, in which case this updates their paths
+                let limit_was_reached = self.watch_limit_reached;
+                let mut unwatched = 0;
+                self.add_directory(path.clone(), &mut unwatched);
+                self.report_unwatched(limit_was_reached, unwatched);
+            }
+
+            if mask & libc::IN_MOVED_FROM != 0 {
+                moved_from.insert(cookie, path);
+            }   else if mask & libc::IN_MOVED_TO != 0 {
+                match moved_from.remove(&cookie) {
+                    Some(from) => events.push(Event::Renamed { from, to: path }),
+                    // Moved from outside of the watched directories
+                    None => events.push(Event::Created(path))
+                }
+            }   else if mask & libc::IN_CREATE != 0 {
+                events.push(Event::Created(path));
+            }   else if mask & libc::IN_DELETE != 0 {
+                events.push(Event::Deleted(path));
+            }   else if mask & (libc::IN_MODIFY | libc::IN_ATTRIB) != 0 {
+                events.push(Event::Modified(path));
+            }
+        }
+
+        // Moved to outside of the watched directories
+        for (_, from) in moved_from {
+            events.push(Event::Deleted(from));
+        }
+
+        events
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
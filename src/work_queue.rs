@@ -0,0 +1,78 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Condvar, Mutex};
+
+// Jobs for a set of workers, any of which can take any job
+// Jobs with the same key are taken one at a time in the order they were pushed, so a job is never taken while an earlier one with its key is still being worked on
+pub struct WorkQueue<K, T> {
+    state: Mutex<State<K, T>>,
+    available: Condvar
+}
+
+struct State<K, T> {
+    jobs: VecDeque<(Option<K>, T)>,
+    // Keys of the jobs that workers are currently working on
+    busy: HashSet<K>,
+    closed: bool
+}
+
+impl<K: Eq + Hash + Copy, T> WorkQueue<K, T> {
+    pub fn new() -> WorkQueue<K, T> {
+        WorkQueue {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                busy: HashSet::new(),
+                closed: false
+            }),
+            available: Condvar::new()
+        }
+    }
+
+    // Jobs without a key can be taken alongside any other job
+    pub fn push(&self, key: Option<K>, job: T) {
+        self.state.lock().unwrap().jobs.push_back((key, job));
+        self.available.notify_one();
+    }
+
+    // Lets the workers stop once they have taken every job that was pushed
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.available.notify_all();
+    }
+
+    // Waits for the oldest job whose key is not being worked on, which must be passed to finish once the job is done
+    // Returns None once the queue has been closed and no jobs are left
+    pub fn pop(&self) -> Option<(Option<K>, T)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let position = {
+                let busy = &state.busy;
+                state.jobs.iter().position(|(key, _)| match key {
+                    Some(key) => !busy.contains(key),
+                    None => true
+                })
+            };
+            if let Some(position) = position {
+                let (key, job) = state.jobs.remove(position).unwrap();
+                if let Some(key) = key {
+                    state.busy.insert(key);
+                }
+                return Some((key, job));
+            }
+            if state.closed && state.jobs.is_empty() {
+                return None;
+            }
+
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    // Lets the next job with the same key be taken
+    pub fn finish(&self, key: Option<K>) {
+        if let Some(key) = key {
+            self.state.lock().unwrap().busy.remove(&key);
+            // The waiting workers may all have been skipping jobs with this key
+            self.available.notify_all();
+        }
+    }
+}